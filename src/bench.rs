@@ -0,0 +1,124 @@
+use crate::bitboard::MoveBit;
+use crate::bitboard_console::board_from_moves;
+use crate::search::{SearchOptions, SearchResult, find_best_move};
+use crate::search_observer::SilentObserver;
+use crate::snapshot::BoardSnapshot;
+use crate::transposition_table::TranspositionTable;
+use crate::util::{FNV_OFFSET_BASIS, fnv1a};
+
+//手番側がF(3,1,5)で必至をかけて勝てる局面。必至や脅威探索のテストでも使う
+pub const BRINKMATE_POSITION: &str =
+    "S 3 0; S 1 0; F 3 0 7; S 3 4; S 4 4; S 1 4; S 1 1; S 3 1; F 4 4 4";
+
+//探索の挙動が変わったかを確かめるための固定の局面(初期局面からの手順)
+pub const BENCH_POSITIONS: [&str; 8] = [
+    "",
+    "S 2 2; S 0 0",
+    "S 2 2; S 0 0; S 2 4; S 1 2",
+    "S 1 1; S 3 3; S 1 3; S 4 1; F 1 1 0",
+    "S 0 0; S 4 4; S 2 2; S 0 4; S 2 0; S 4 0",
+    "S 3 0; S 1 0; F 3 0 7; S 3 4; S 4 4; S 1 4",
+    "S 2 0; S 0 0; S 0 4; S 2 2; S 0 2; S 2 4; F 0 4 2; F 2 2 1",
+    BRINKMATE_POSITION,
+];
+
+pub struct BenchResult {
+    pub results: Vec<SearchResult>, //BENCH_POSITIONSの順
+    pub nodes: usize,
+    pub signature: u64,
+}
+
+//FNV-1aで探索結果(最善手・評価値・読み筋・ノード数)を混ぜる
+fn mix(hash: &mut u64, value: u64) {
+    *hash = fnv1a(*hash, &value.to_le_bytes());
+}
+
+fn mix_move(hash: &mut u64, mv: &MoveBit) {
+    mix(hash, (mv.idx as u64) << 8 | mv.angle_idx as u64);
+}
+
+//固定の局面を再現可能な設定で探索し、結果のシグネチャを求める。
+//同じ探索設定・評価関数なら常に同じ値になり、探索の挙動が変わったときだけ変わる
+pub fn run_bench<F>(options: &SearchOptions, hash_mb: usize, evaluate: &F) -> BenchResult
+where
+    F: Fn(&BoardSnapshot) -> i16 + Sync,
+{
+    let options = SearchOptions {
+        deterministic: true,
+        ..options.clone()
+    };
+    let tt = TranspositionTable::new(hash_mb);
+    let mut signature = FNV_OFFSET_BASIS;
+    let mut nodes = 0;
+    let results = BENCH_POSITIONS
+        .iter()
+        .map(|moves| {
+            let (mut board, history) = board_from_moves(moves).unwrap();
+            let result = find_best_move(
+                &mut board,
+                &options,
+                &tt,
+                &history,
+                evaluate,
+                &SilentObserver,
+            );
+            if let Some(mv) = &result.best_move {
+                mix_move(&mut signature, mv);
+            }
+            mix(&mut signature, result.score as u64);
+            result.pv.iter().for_each(|mv| mix_move(&mut signature, mv));
+            mix(&mut signature, result.nodes as u64);
+            nodes += result.nodes;
+            result
+        })
+        .collect();
+    BenchResult {
+        results,
+        nodes,
+        signature,
+    }
+}
+
+#[test]
+fn test_bench_is_reproducible() {
+    use crate::search::test_evaluate;
+
+    //スレッド数や時間制限を指定しても再現可能な設定が優先される
+    let options = SearchOptions {
+        depth: 4,
+        threads: 4,
+        time_limit: Some(std::time::Duration::from_millis(1)),
+        ..Default::default()
+    };
+    let first = run_bench(&options, 1, &test_evaluate);
+    let second = run_bench(&options, 1, &test_evaluate);
+    assert_eq!(first.signature, second.signature);
+    assert_eq!(first.nodes, second.nodes);
+    for (a, b) in first.results.iter().zip(&second.results) {
+        assert_eq!(a.best_move, b.best_move);
+        assert_eq!(a.score, b.score);
+        assert_eq!(a.pv, b.pv);
+        assert_eq!(a.nodes, b.nodes);
+        assert!(a.best_move.is_some());
+    }
+
+    //前の探索が残った置換表を渡しても同じ結果になる
+    let tt = TranspositionTable::new(1);
+    let (mut board, history) = board_from_moves(BENCH_POSITIONS[1]).unwrap();
+    let deterministic = SearchOptions {
+        deterministic: true,
+        ..options.clone()
+    };
+    for _ in 0..2 {
+        let result = find_best_move(
+            &mut board,
+            &deterministic,
+            &tt,
+            &history,
+            &test_evaluate,
+            &SilentObserver,
+        );
+        assert_eq!(result.pv, first.results[1].pv);
+        assert_eq!(result.nodes, first.results[1].nodes);
+    }
+}
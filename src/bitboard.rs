@@ -1,4 +1,5 @@
 use arrayvec::ArrayVec;
+use serde::{Deserialize, Serialize};
 
 use crate::{
     eval_value::{Eval, EvalValue},
@@ -13,7 +14,7 @@ pub struct Bitboard {
     pub turn: i8, // 1が先手, -1が後手
 }
 
-#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
 pub struct MoveBit {
     pub idx: u8,
     pub angle_idx: u8, //8以上のときはset
@@ -30,7 +31,7 @@ impl MoveBit {
             format!("S({},{})", r, c)
         }
     }
-    pub fn vec_to_string(moves: &Vec<MoveBit>) -> String {
+    pub fn vec_to_string(moves: &[MoveBit]) -> String {
         let mut result = String::new();
         for &mv in moves {
             result += &mv.to_string();
@@ -38,7 +39,7 @@ impl MoveBit {
         }
         result
     }
-    pub fn print_vec_to_string(moves: &Vec<MoveBit>) {
+    pub fn print_vec_to_string(moves: &[MoveBit]) {
         let text = Self::vec_to_string(moves);
         println!("legal_moves: {}\nlen: {}", text, moves.len());
     }
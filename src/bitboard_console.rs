@@ -31,6 +31,51 @@ fn format_with_underscores(n: u64) -> String {
     result
 }
 
+//"S 2 2" / "F 2 2 0" の形式(MoveBit::to_stringの "S(2,2)" / "F(2,2,0)" も可)を手に変換する
+pub fn parse_move(text: &str) -> Option<MoveBit> {
+    let set_re = Regex::new(r"[sS][\s(]*(\d+)[\s,]+(\d+)").unwrap();
+    let flick_re = Regex::new(r"[fF][\s(]*(\d+)[\s,]+(\d+)[\s,]+(\d)").unwrap();
+
+    let (r, c, angle_idx) = if let Some(caps) = set_re.captures(text) {
+        (caps[1].parse::<u8>().ok()?, caps[2].parse::<u8>().ok()?, 8)
+    } else if let Some(caps) = flick_re.captures(text) {
+        (
+            caps[1].parse::<u8>().ok()?,
+            caps[2].parse::<u8>().ok()?,
+            caps[3].parse::<u8>().ok()?,
+        )
+    } else {
+        return None;
+    };
+
+    //盤外の座標は受け付けない
+    if r as u64 >= FIELD_BOD_HEIGHT || c as u64 >= FIELD_BOD_WIDTH {
+        return None;
+    }
+    Some(MoveBit::new(r, c, angle_idx))
+}
+
+//初期局面から手順を適用した局面と、それまでに現れた局面のハッシュ(古い順)を返す。手順は ; 区切り
+pub fn board_from_moves(moves: &str) -> Result<(Bitboard, Vec<u64>), String> {
+    let mut board = Bitboard::new_initial();
+    let mut history: Vec<u64> = Vec::new();
+    for text in moves.split(';').filter(|t| !t.trim().is_empty()) {
+        let mv = parse_move(text).ok_or(format!("手を読み取れません: {}", text.trim()))?;
+        if board.game_over() || !board.iter_legal_move().any(|m| m == mv) {
+            return Err(format!("非合法手です: {}", mv.to_string()));
+        }
+        let hash = board.to_compression_bod();
+        if board
+            .apply_force_with_check_illegal_move(mv, history.last().copied())
+            .is_err()
+        {
+            return Err(format!("千日手になる手です: {}", mv.to_string()));
+        }
+        history.push(hash);
+    }
+    Ok((board, history))
+}
+
 pub trait BitboardConsole {
     fn to_string(&self) -> String;
     fn read_to_move() -> MoveBit;
@@ -79,31 +124,11 @@ impl BitboardConsole for Bitboard {
         return buf;
     }
     fn read_to_move() -> MoveBit {
-        let set_re = Regex::new(r"[sS]\s+(\d+)\s+(\d+)").unwrap();
-        let flick_re = Regex::new(r"[fF]\s+(\d+)\s+(\d+)\s+(\d)").unwrap();
-
         loop {
             let read_buf = read_buffer();
 
-            match set_re.captures(&read_buf) {
-                Some(caps) => {
-                    return MoveBit::new(
-                        caps[1].parse::<u8>().unwrap(),
-                        caps[2].parse::<u8>().unwrap(),
-                        8,
-                    );
-                }
-                None => (),
-            }
-            match flick_re.captures(&read_buf) {
-                Some(caps) => {
-                    return MoveBit::new(
-                        caps[1].parse::<u8>().unwrap(),
-                        caps[2].parse::<u8>().unwrap(),
-                        caps[3].parse::<u8>().unwrap(),
-                    );
-                }
-                None => (),
+            if let Some(mv) = parse_move(&read_buf) {
+                return mv;
             }
             println!(
                 "コマンドの読み取りに失敗しました。\ncommands:\n    S c r\n    F c r angle_idx"
@@ -1,9 +1,9 @@
+use std::collections::HashSet;
+
 use crate::bitboard::MoveList;
 
 use super::bitboard::{Bitboard, MoveBit};
 use super::eval_value::EvalValue;
-use Vec;
-use std::usize;
 
 pub fn find_mate_in_one_move(vidro: &mut Bitboard, prev_hash: Option<u64>) -> Option<MoveBit> {
     let moves = vidro.iter_legal_move(); //詰み探索には千日手を除くことはしなくてよい。積んでいる局面になったときに千日手盤面になることはないため
@@ -26,332 +26,393 @@ pub fn find_mate_in_one_move(vidro: &mut Bitboard, prev_hash: Option<u64>) -> Op
     None
 }
 
-//先後最善を指した時の詰み手順
-pub fn find_mate_sequence(
-    vidro: &mut Bitboard,
-    max_depth: usize,
-    prev_hash: Option<u64>,
-) -> Option<Vec<MoveBit>> {
-    if vidro.have_piece[((-vidro.turn + 1) / 2) as usize] > 2 {
-        return None;
-    }
-
-    let mut sequence = Vec::new();
-    //詰みがあるかどうかをしらべてある場合は手順を構築する
-    let result =
-        find_mate_sequence_recursive(vidro, max_depth, usize::MIN, usize::MAX, true, prev_hash);
-
-    if let Some((_, first_move)) = result {
-        //手順構築
-        sequence.push(first_move);
+pub fn is_reach(vidro: &mut Bitboard, prev_hash: Option<u64>) -> bool {
+    vidro.turn_change(); //意図的に手番を書き換え2手差しさせたときに勝利することがあるかを調べる
+    let result = checkmate_in_one_move(vidro, prev_hash);
+    vidro.turn_change(); //手番を戻す
+    result
+}
 
+pub fn checkmate_in_one_move(vidro: &mut Bitboard, prev_hash: Option<u64>) -> bool {
+    let moves = vidro.iter_legal_flick_move();
+    let turn = vidro.turn;
+    for mv in moves {
         if vidro
-            .apply_force_with_check_illegal_move(first_move, prev_hash)
+            .apply_force_with_check_illegal_move(mv, prev_hash)
             .is_err()
         {
-            panic!("move to apply must not be illegal move");
-        };
-
-        let mut idx = 0;
-        while !vidro.win_eval().evaluated {
-            if sequence.len() >= max_depth {
-                break;
-            }
-
-            let remaining_depth = (max_depth - sequence.len()) as usize;
-
-            //受け手と攻め手を入れ替え
-            let is_attacker = sequence.len() % 2 == 0;
-            if let Some((_, best_next_move)) = find_mate_sequence_recursive(
-                vidro,
-                remaining_depth,
-                usize::MIN,
-                usize::MAX,
-                is_attacker,
-                prev_hash,
-            ) {
-                if vidro
-                    .apply_force_with_check_illegal_move(best_next_move, prev_hash)
-                    .is_err()
-                {
-                    panic!("move to apply must not be illegal move");
-                };
-                sequence.push(best_next_move);
-            } else {
-                //手順が見つからなかった(バグの可能性が高い)
-                for &mv in sequence.iter().rev() {
-                    vidro.undo_force(mv);
-                }
-                return None;
+            continue;
+        }
+        if let EvalValue::Win(value) = vidro.win_eval().value {
+            if value as i8 == turn {
+                vidro.undo_force(mv);
+                return true;
             }
-            idx += 1;
         }
+        vidro.undo_force(mv);
+    }
+    false
+}
+
+pub fn generate_threat_moves(vidro: &mut Bitboard, prev_hash: Option<u64>) -> MoveList {
+    let mut moves = MoveList::new();
+    vidro.generate_legal_moves(&mut moves);
 
-        //見つかった手順を使って盤面を呼び出し前の状態に復元
-        for &mv in sequence.iter().rev() {
+    let hash = vidro.to_compression_bod();
+    moves.retain(|&mut mv| {
+        if vidro
+            .apply_force_with_check_illegal_move(mv, prev_hash)
+            .is_err()
+        {
+            return false;
+        }
+        //詰めろ(自殺手を除く)
+        if is_reach(vidro, prev_hash) && !checkmate_in_one_move(vidro, Some(hash)) {
             vidro.undo_force(mv);
+            return true;
         }
+        vidro.undo_force(mv);
+        false
+    });
+    moves
+}
 
-        Some(sequence)
-    } else {
-        None
+//必至: 手番側(受け方)がどの手を指しても相手に1手勝ちが残る局面
+//is_draw: 受け方が指した後の局面が千日手になるか
+pub fn is_brinkmate(
+    vidro: &mut Bitboard,
+    prev_hash: Option<u64>,
+    is_draw: impl Fn(u64) -> bool,
+) -> bool {
+    //決着済みか、受け方が先に勝てる
+    if vidro.game_over() || checkmate_in_one_move(vidro, prev_hash) {
+        return false;
+    }
+    let hash = vidro.to_compression_bod();
+    let attacker = -vidro.turn;
+    for mv in vidro.iter_legal_move() {
+        if vidro
+            .apply_force_with_check_illegal_move(mv, prev_hash)
+            .is_err()
+        {
+            continue;
+        }
+        let escaped = match vidro.win_eval().value {
+            //自分で相手の列を完成させた
+            EvalValue::Win(value) if value as i8 == attacker => false,
+            EvalValue::Win(_) | EvalValue::Draw => true,
+            _ => is_draw(vidro.to_compression_bod()) || !checkmate_in_one_move(vidro, Some(hash)),
+        };
+        vidro.undo_force(mv);
+        if escaped {
+            return false;
+        }
     }
+    true
 }
 
-fn find_mate_sequence_recursive(
+//必至をかける手(受け方がどう指しても次に1手勝ちが残る詰めろ)
+//history: これまでに現れた局面(受け方がそこへ戻れば千日手で逃れる)
+pub fn generate_brinkmate_moves(
     vidro: &mut Bitboard,
-    depth: usize,
-    alpha: usize,
-    beta: usize,
-    is_attacker: bool,
     prev_hash: Option<u64>,
-) -> Option<(usize, MoveBit)> {
+    history: &[u64],
+) -> MoveList {
+    let mut moves = generate_threat_moves(vidro, prev_hash);
     let hash = vidro.to_compression_bod();
+    moves.retain(|&mut mv| {
+        vidro
+            .apply_force_with_check_illegal_move(mv, prev_hash)
+            .unwrap();
+        let result = is_brinkmate(vidro, Some(hash), |h| history.contains(&h));
+        vidro.undo_force(mv);
+        result
+    });
+    moves
+}
 
-    //一手詰め判定
-    if is_attacker {
-        if let Some(mv) = find_mate_in_one_move(vidro, prev_hash) {
-            return Some((depth, mv));
+//脅威空間探索の設定
+#[derive(Clone, Debug)]
+pub struct ThreatSearchOptions {
+    pub max_depth: usize, //攻め方の勝ちまでの最大手数
+    pub max_nodes: usize,
+}
+
+impl Default for ThreatSearchOptions {
+    fn default() -> Self {
+        Self {
+            max_depth: 31,
+            max_nodes: 1_000_000,
         }
     }
+}
 
-    if depth == 0 {
-        return None;
-    }
+#[derive(Clone, Debug, PartialEq)]
+pub enum ThreatSearchResult {
+    Win(Vec<MoveBit>), //詰めろを続けて勝つ手順(受け方は最も長く逃れる手)
+    NotFound,
+    Aborted, //ノード数の上限に達した
+}
 
-    let mut alpha = alpha;
-    let mut beta = beta;
+//脅威空間探索: 攻め方は詰めろの手だけ、受け方は詰めろを解除する手だけを読んで、連続する詰めろによる勝ちを探す
+//history: これまでに現れた局面(古い順、最後が直前の局面)。戻れば千日手で受け方が逃れる
+pub fn threat_space_search(
+    vidro: &mut Bitboard,
+    history: &[u64],
+    options: &ThreatSearchOptions,
+) -> (ThreatSearchResult, usize) {
+    let mut search = ThreatSpaceSearch {
+        max_nodes: options.max_nodes,
+        nodes: 0,
+        failed: HashSet::new(),
+        seen: history.to_vec(),
+        rep_hits: 0,
+    };
+    let prev_hash = history.last().copied();
+    //短い勝ちから順に探す
+    for depth in (1..=options.max_depth).step_by(2) {
+        if let Some(line) = search.attack(vidro, prev_hash, depth) {
+            return (ThreatSearchResult::Win(line), search.nodes);
+        }
+        if search.nodes >= search.max_nodes {
+            return (ThreatSearchResult::Aborted, search.nodes);
+        }
+    }
+    (ThreatSearchResult::NotFound, search.nodes)
+}
 
-    if is_attacker {
-        //見つかったときのdepthが大きい物(短く詰ませる)手を探す
-        let attacking_moves = vidro.iter_legal_move();
+struct ThreatSpaceSearch {
+    max_nodes: usize,
+    nodes: usize,
+    failed: HashSet<(u64, Option<u64>, usize)>, //(局面, 直前の局面, 残り手数)で勝てなかった局面
+    seen: Vec<u64>,                             //対局中に現れた局面と探索経路
+    rep_hits: usize, //千日手で受け方が逃れた回数(経路に依存して勝てなかった局面を記録しないため)
+}
 
-        let mut max_depth_found = usize::MIN; //最終的な詰みの深さ
-        let mut best_move: Option<MoveBit> = None;
+impl ThreatSpaceSearch {
+    //攻め方の手番。depth手以内に勝てれば手順を返す
+    fn attack(
+        &mut self,
+        vidro: &mut Bitboard,
+        prev_hash: Option<u64>,
+        depth: usize,
+    ) -> Option<Vec<MoveBit>> {
+        self.nodes += 1;
+        if let Some(mv) = find_mate_in_one_move(vidro, prev_hash) {
+            return Some(vec![mv]);
+        }
+        let hash = vidro.to_compression_bod();
+        let key = (hash, prev_hash, depth);
+        if depth < 3 || self.nodes >= self.max_nodes || self.failed.contains(&key) {
+            return None;
+        }
 
-        for mv in attacking_moves {
-            if vidro
+        self.seen.push(hash);
+        let rep_hits = self.rep_hits;
+        let mut result = None;
+        for mv in generate_threat_moves(vidro, prev_hash) {
+            vidro
                 .apply_force_with_check_illegal_move(mv, prev_hash)
-                .is_err()
-            {
-                continue;
+                .unwrap();
+            let line = self.defend(vidro, Some(hash), depth - 1);
+            vidro.undo_force(mv);
+            if let Some(mut line) = line {
+                line.insert(0, mv);
+                result = Some(line);
+                break;
             }
-            let result =
-                find_mate_sequence_recursive(vidro, depth - 1, alpha, beta, false, Some(hash));
-            vidro.undo_force(mv); //ミスを防ぐためにすぐ戻す
-            //
-            // 相手の手番で再帰呼び出し
-            if let Some((found_depth, _)) = result {
-                if max_depth_found < found_depth {
-                    //最善が更新された
-                    max_depth_found = found_depth;
-                    best_move = Some(mv.clone());
-                }
-                alpha = alpha.max(max_depth_found);
-                if alpha >= beta {
-                    break;
-                }
+            if self.nodes >= self.max_nodes {
+                break;
             }
         }
+        self.seen.pop();
+        //打ち切りや千日手で見つからなかった局面は記録しない
+        if result.is_none() && self.nodes < self.max_nodes && self.rep_hits == rep_hits {
+            self.failed.insert(key);
+        }
+        result
+    }
+
+    //受け方の手番(詰めろをかけられている)。どう受けても勝てれば最も長い手順を返す
+    fn defend(
+        &mut self,
+        vidro: &mut Bitboard,
+        prev_hash: Option<u64>,
+        depth: usize,
+    ) -> Option<Vec<MoveBit>> {
+        self.nodes += 1;
+        if checkmate_in_one_move(vidro, prev_hash) {
+            return None;
+        }
+        let hash = vidro.to_compression_bod();
+        let attacker = -vidro.turn;
 
-        best_move.map(|mv| (max_depth_found, mv))
-    } else {
-        //見つかったときのdepthが小さい物(長く詰まされる)手を探す
-        //特に効率の良い守る手を見つける方法はないため合法手から絞り込むことにする
-        let defending_moves = vidro.iter_legal_move();
-        //合法手が一つもないということは起きないため空の場合は考えない
-        // if defending_moves.is_empty() {
-        // return Some(depth + 1);
-        // }
-
-        let mut min_depth_found = usize::MAX;
-        let mut best_move: Option<MoveBit> = None;
-
-        //相手の手番で再帰呼び出し
-        for mv in defending_moves {
+        //詰めろを解除する手だけを残す
+        let mut answers = Vec::new();
+        let mut losing_line = Vec::new();
+        for mv in vidro.iter_legal_move() {
             if vidro
                 .apply_force_with_check_illegal_move(mv, prev_hash)
                 .is_err()
             {
                 continue;
             }
-            let result =
-                find_mate_sequence_recursive(vidro, depth - 1, alpha, beta, true, Some(hash));
-            vidro.undo_force(mv);
-
-            if result.is_none() {
-                return None;
-            }
-
-            if let Some((found_depth, _)) = result {
-                //詰む場合
-                if found_depth < min_depth_found {
-                    //最善が更新された
-                    min_depth_found = found_depth;
-                    best_move = Some(mv.clone());
+            let escaped = match vidro.win_eval().value {
+                //自分で相手の列を完成させた
+                EvalValue::Win(value) if value as i8 == attacker => {
+                    if losing_line.is_empty() {
+                        losing_line = vec![mv];
+                    }
+                    false
                 }
-                beta = beta.min(min_depth_found);
-                if beta <= alpha {
-                    break;
+                EvalValue::Win(_) | EvalValue::Draw => true,
+                _ if self.seen.contains(&vidro.to_compression_bod()) => {
+                    self.rep_hits += 1;
+                    true
                 }
+                _ => match find_mate_in_one_move(vidro, Some(hash)) {
+                    Some(mate) => {
+                        if losing_line.len() < 2 {
+                            losing_line = vec![mv, mate];
+                        }
+                        false
+                    }
+                    None => {
+                        answers.push(mv);
+                        false
+                    }
+                },
+            };
+            vidro.undo_force(mv);
+            if escaped {
+                return None;
             }
         }
 
-        //ここで詰まない場合が一つもない
-        //すなわち必ず詰むのでvalueを返却
-        best_move.map(|mv| (min_depth_found, mv))
-    }
-}
-
-// main関数などから呼び出すためのラッパー関数
-pub fn find_mate(
-    vidro: &mut Bitboard,
-    max_depth: usize,
-    prev_hash: Option<u64>,
-) -> Option<MoveBit> {
-    let mut mate_move = MoveBit::from_idx(0, 8);
-    if find_mate_recursive(vidro, max_depth, &mut mate_move, prev_hash) {
-        Some(mate_move)
-    } else {
-        None
-    }
-}
-
-// 詰み探索の本体（再帰関数）
-fn find_mate_recursive(
-    vidro: &mut Bitboard,
-    depth: usize,
-    mate_move: &mut MoveBit,
-    prev_hash: Option<u64>,
-) -> bool {
-    //深さ切れ(詰みなしと判断)
-    if depth == 0 {
-        return false;
-    }
-
-    let hash = vidro.to_compression_bod();
-
-    let attacking_moves = vidro.iter_legal_move();
-
-    //OR探索
-    for mv in attacking_moves {
-        if vidro
-            .apply_force_with_check_illegal_move(mv, prev_hash)
-            .is_err()
-        {
-            continue;
+        if answers.is_empty() {
+            //どう指しても次に負ける
+            return Some(losing_line);
         }
 
-        //受けが無くなっているかどうかを調べる
-        if check_opponent_defense(vidro, depth - 1, mate_move, Some(hash)) {
-            //受けがないことが確定 == 詰みが見つかった
+        self.seen.push(hash);
+        let mut longest: Option<Vec<MoveBit>> = None;
+        for mv in answers {
+            vidro
+                .apply_force_with_check_illegal_move(mv, prev_hash)
+                .unwrap();
+            let line = self.attack(vidro, Some(hash), depth - 1);
             vidro.undo_force(mv);
-            *mate_move = mv.clone(); //最後の代入の値==最初に指す手==詰み手順に入る時の手
-            return true;
+            let Some(mut line) = line else {
+                longest = None;
+                break;
+            };
+            line.insert(0, mv);
+            if longest.as_ref().is_none_or(|l| l.len() < line.len()) {
+                longest = Some(line);
+            }
         }
-        vidro.undo_force(mv);
+        self.seen.pop();
+        longest
     }
-
-    //どの手も詰みにならなかった
-    false
 }
 
-//NOTE! 詰みの読み筋を相手の物も含めるようにする
+#[test]
+fn test_brinkmate_moves() {
+    use crate::bench::BRINKMATE_POSITION;
+    use crate::bitboard_console::board_from_moves;
 
-//受けがないかどうか
-fn check_opponent_defense(
-    vidro: &mut Bitboard,
-    depth: usize,
-    mate_move: &mut MoveBit,
-    prev_hash: Option<u64>,
-) -> bool {
-    //勝になっていないかを確認
-    if let EvalValue::Win(v) = vidro.win_eval().value {
-        if v as i8 == -vidro.turn {
-            return true;
-        }
-    }
-
-    if depth == 0 {
-        return false;
-    }
+    let (mut vidro, history) = board_from_moves(BRINKMATE_POSITION).unwrap();
+    let prev_hash = history.last().copied();
+    let attacker = vidro.turn;
+    let moves = generate_brinkmate_moves(&mut vidro, prev_hash, &history);
+    assert_eq!(moves.len(), 1);
+    assert_eq!(moves[0].to_string(), "F(3,1,5)");
 
+    //どう受けても攻め方に1手勝ちが残る
     let hash = vidro.to_compression_bod();
-
-    let defending_moves = vidro.iter_legal_move();
-
-    // 生成した受け手の全ての応手に対して、詰み手順が続くか調べる (AND検索)
-    for mv in defending_moves {
+    vidro
+        .apply_force_with_check_illegal_move(moves[0], prev_hash)
+        .unwrap();
+    let after = vidro.to_compression_bod();
+    for mv in vidro.iter_legal_move() {
         if vidro
-            .apply_force_with_check_illegal_move(mv, prev_hash)
+            .apply_force_with_check_illegal_move(mv, Some(hash))
             .is_err()
         {
             continue;
         }
-
-        // 自分が再度攻めて詰むかどうかを再帰的に調べる
-        let can_mate = find_mate_recursive(vidro, depth - 1, mate_move, Some(hash));
-
+        assert!(match vidro.win_eval().value {
+            EvalValue::Win(value) => value as i8 == attacker,
+            EvalValue::Draw => false,
+            _ => find_mate_in_one_move(&mut vidro, Some(after)).is_some(),
+        });
         vidro.undo_force(mv);
-
-        if !can_mate {
-            // 相手のこの受けで詰みが途切れた。
-            // したがって、元の自分の手は必勝の詰み手順ではない。
-            return false;
-        }
     }
 
-    // 相手がどう受けても、全て詰み手順が続くことが証明された
-    true
-}
-
-pub fn is_reach(vidro: &mut Bitboard, prev_hash: Option<u64>) -> bool {
-    vidro.turn_change(); //意図的に手番を書き換え2手差しさせたときに勝利することがあるかを調べる
-    let result = checkmate_in_one_move(vidro, prev_hash);
-    vidro.turn_change(); //手番を戻す
-    result
+    let mut initial = Bitboard::new_initial();
+    assert!(!is_brinkmate(&mut initial, None, |_| false));
 }
 
-pub fn checkmate_in_one_move(vidro: &mut Bitboard, prev_hash: Option<u64>) -> bool {
-    let moves = vidro.iter_legal_flick_move();
-    let turn = vidro.turn;
-    for mv in moves {
-        if vidro
-            .apply_force_with_check_illegal_move(mv, prev_hash)
-            .is_err()
-        {
-            continue;
-        }
-        if let EvalValue::Win(value) = vidro.win_eval().value {
-            if value as i8 == turn {
-                vidro.undo_force(mv);
-                return true;
-            }
-        }
-        vidro.undo_force(mv);
+#[test]
+fn test_threat_space_search_line_wins() {
+    use crate::bench::BRINKMATE_POSITION;
+    use crate::bitboard_console::board_from_moves;
+    use crate::game::Game;
+
+    let (mut vidro, history) = board_from_moves(BRINKMATE_POSITION).unwrap();
+    let attacker = vidro.turn;
+    let (result, _) = threat_space_search(&mut vidro, &history, &ThreatSearchOptions::default());
+    let ThreatSearchResult::Win(line) = result else {
+        panic!("勝ち筋が見つからない");
+    };
+    assert_eq!(line[0].to_string(), "F(3,1,5)");
+
+    //読み筋を実際に指すと攻め方が勝つ
+    let mut game = Game::from_position(vidro, history);
+    for mv in line {
+        game.apply(mv).unwrap();
     }
-    false
+    assert_eq!(game.board.win_turn(), attacker as i16);
 }
 
-pub fn generate_threat_moves(vidro: &mut Bitboard, prev_hash: Option<u64>) -> MoveList {
-    let mut moves = MoveList::new();
-    vidro.generate_legal_moves(&mut moves);
+#[test]
+fn test_threat_space_search_does_not_cache_repetition_escape() {
+    use crate::bench::BRINKMATE_POSITION;
+    use crate::bitboard_console::board_from_moves;
 
+    let (mut vidro, history) = board_from_moves(BRINKMATE_POSITION).unwrap();
+    let prev_hash = history.last().copied();
     let hash = vidro.to_compression_bod();
-    moves.retain(|&mut mv| {
-        if vidro
-            .apply_force_with_check_illegal_move(mv, prev_hash)
-            .is_err()
-        {
-            return false;
-        }
-        //詰めろ(自殺手を除く)
-        if is_reach(vidro, prev_hash) && !checkmate_in_one_move(vidro, Some(hash)) {
+
+    //詰めろの後、受け方が探索経路の局面へ戻れることにする
+    let threat = generate_brinkmate_moves(&mut vidro, prev_hash, &history)[0];
+    vidro
+        .apply_force_with_check_illegal_move(threat, prev_hash)
+        .unwrap();
+    let escape = vidro
+        .iter_legal_move()
+        .find_map(|mv| {
+            vidro
+                .apply_force_with_check_illegal_move(mv, Some(hash))
+                .ok()?;
+            let escape = vidro.to_compression_bod();
+            let ok = matches!(vidro.win_eval().value, EvalValue::Unknown);
             vidro.undo_force(mv);
-            return true;
-        }
-        vidro.undo_force(mv);
-        false
-    });
-    moves
+            ok.then_some(escape)
+        })
+        .unwrap();
+    vidro.undo_force(threat);
+
+    let mut search = ThreatSpaceSearch {
+        max_nodes: 1_000_000,
+        nodes: 0,
+        failed: HashSet::new(),
+        seen: [history.as_slice(), &[escape]].concat(),
+        rep_hits: 0,
+    };
+    assert_eq!(search.attack(&mut vidro, prev_hash, 3), None);
+
+    //経路が変われば同じ局面・残り手数でも勝てる
+    search.seen = history.clone();
+    assert!(search.attack(&mut vidro, prev_hash, 3).is_some());
 }
@@ -0,0 +1,675 @@
+use std::collections::HashMap;
+
+use serde_json::json;
+
+use crate::bitboard::{Bitboard, MoveBit};
+use crate::checkmate_search::{
+    checkmate_in_one_move, find_mate_in_one_move, generate_threat_moves,
+};
+use crate::eval_value::EvalValue;
+
+//証明数・反証数の無限大
+const INF: u32 = 100_000_000;
+
+#[derive(Clone, Copy, Debug)]
+struct DfpnEntry {
+    pn: u32,
+    dn: u32,
+    mate_len: u16,       //証明済みのとき、勝ちまでの手数
+    by_repetition: bool, //千日手による不詰。経路に依存するので他の経路からは使わない
+}
+
+impl DfpnEntry {
+    const UNKNOWN: Self = Self::new(1, 1, 0);
+    const fn new(pn: u32, dn: u32, mate_len: u16) -> Self {
+        Self {
+            pn,
+            dn,
+            mate_len,
+            by_repetition: false,
+        }
+    }
+    fn proven(mate_len: u16) -> Self {
+        Self::new(0, INF, mate_len)
+    }
+    fn disproven() -> Self {
+        Self::new(INF, 0, 0)
+    }
+    fn is_solved(&self) -> bool {
+        self.pn == 0 || (self.dn == 0 && !self.by_repetition)
+    }
+}
+
+//(局面, 直前の局面, 残り手数)。直前の局面に戻る手は指せないので、直前の局面で合法手が変わる
+type DfpnKey = (u64, Option<u64>, u8);
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum MateResult {
+    Mate(Vec<MoveBit>), //最短の詰み手順(受けは最も長く逃れる手を選ぶ)
+    NoMate,             //max_depth手以内に詰みはない(攻め方は勝つ手と詰めろの手だけを読む)
+    Unknown,            //ノード数の上限に達した
+}
+
+//詰ませる手と、その手から勝ちまでの手数
+#[derive(Clone, Debug)]
+pub struct MatingMove {
+    pub mv: MoveBit,
+    pub mate_len: usize,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct MateAnalysis {
+    pub mating_moves: Vec<MatingMove>, //短い順
+    pub unresolved: Vec<MoveBit>,      //ノード数の上限で詰むか分からなかった手
+}
+
+//詰みの証明木。攻め方の手には受け方のすべての応手が、受け方の手には攻め方の最短の応手が続く
+#[derive(Clone, Debug)]
+pub struct ProofTree {
+    pub mv: MoveBit,
+    pub mate_len: usize, //この手から勝ちまでの手数
+    pub replies: Vec<ProofTree>,
+}
+
+impl ProofTree {
+    pub fn to_json(&self) -> serde_json::Value {
+        json!({
+            "move": self.mv.to_string(),
+            "mate_len": self.mate_len,
+            "replies": self.replies.iter().map(|r| r.to_json()).collect::<Vec<_>>(),
+        })
+    }
+
+    //1手1行で、応手を字下げして表示する
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        self.render_into(0, &mut out);
+        out
+    }
+
+    fn render_into(&self, indent: usize, out: &mut String) {
+        out.push_str(&format!(
+            "{}{} ({}手)\n",
+            "  ".repeat(indent),
+            self.mv.to_string(),
+            self.mate_len
+        ));
+        for reply in &self.replies {
+            reply.render_into(indent + 1, out);
+        }
+    }
+
+    //ノード数
+    pub fn size(&self) -> usize {
+        1 + self.replies.iter().map(|r| r.size()).sum::<usize>()
+    }
+}
+
+enum Proof {
+    Proven(usize), //勝ちまでの手数
+    Disproven,
+    Unknown,
+}
+
+//df-pnによる詰み探索。攻め方は手番側で、勝つ手か詰めろの手だけを指す
+pub struct DfpnSolver {
+    tt: HashMap<DfpnKey, DfpnEntry>,
+    max_nodes: usize, //solveとproof_tree1回、analyzeの1手あたりのノード数の上限
+    nodes: usize,     //これまでの呼び出しの合計
+    node_limit: usize,
+    exhausted: bool,
+    attacker: i8,
+    proof_depth: usize, //置換表が詰みを証明している手数の上限(shortest_mateの結果)
+    seen: Vec<u64>,     //対局中に現れた局面と探索経路(千日手の判定用)
+}
+
+impl DfpnSolver {
+    pub fn new(max_nodes: usize) -> Self {
+        Self {
+            tt: HashMap::new(),
+            max_nodes,
+            nodes: 0,
+            node_limit: 0,
+            exhausted: false,
+            attacker: 1,
+            proof_depth: 0,
+            seen: Vec::new(),
+        }
+    }
+
+    pub fn nodes(&self) -> usize {
+        self.nodes
+    }
+
+    //ノード数の上限で打ち切った探索があったか。あれば見つけた詰みが最短とは限らない
+    pub fn exhausted(&self) -> bool {
+        self.exhausted
+    }
+
+    //置換表などを空にして使い回す。ノード数の合計はそのまま
+    pub fn clear(&mut self) {
+        self.tt.clear();
+        self.seen.clear();
+        self.exhausted = false;
+    }
+
+    //ここからmax_nodesまで探索する
+    fn start_call(&mut self) {
+        self.node_limit = self.nodes + self.max_nodes;
+    }
+
+    //history: これまでに現れた局面(古い順、最後が直前の局面)
+    pub fn solve(&mut self, board: &mut Bitboard, history: &[u64], max_depth: usize) -> MateResult {
+        self.start_call();
+        self.attacker = board.turn;
+        match self.shortest_mate(board, history, max_depth) {
+            Proof::Proven(_) => MateResult::Mate(self.extract_pv(board, history, self.proof_depth)),
+            Proof::Disproven => MateResult::NoMate,
+            Proof::Unknown => MateResult::Unknown,
+        }
+    }
+
+    //max_depth手以内に詰ませる手をすべて、短い順に求める
+    pub fn analyze(
+        &mut self,
+        board: &mut Bitboard,
+        history: &[u64],
+        max_depth: usize,
+    ) -> MateAnalysis {
+        let attacker = board.turn;
+        let prev_hash = history.last().copied();
+        let hash = board.to_compression_bod();
+        let mut child_history = history.to_vec();
+        child_history.push(hash);
+
+        let mut analysis = MateAnalysis::default();
+        let moves: Vec<MoveBit> = board
+            .iter_legal_move()
+            .filter(|&mv| !board.check_illegal_move(mv, prev_hash))
+            .collect();
+        for mv in moves {
+            board
+                .apply_force_with_check_illegal_move(mv, prev_hash)
+                .unwrap();
+            let proof = match board.win_eval().value {
+                EvalValue::Win(winner) if winner as i8 == attacker => Proof::Proven(0),
+                EvalValue::Win(_) | EvalValue::Draw => Proof::Disproven,
+                _ if history.contains(&board.to_compression_bod()) => Proof::Disproven,
+                //受け方の手番から解く。上限は手ごと
+                _ => {
+                    self.start_call();
+                    self.attacker = attacker;
+                    self.shortest_mate(board, &child_history, max_depth.saturating_sub(1))
+                }
+            };
+            board.undo_force(mv);
+            match proof {
+                Proof::Proven(len) => analysis.mating_moves.push(MatingMove {
+                    mv,
+                    mate_len: len + 1,
+                }),
+                Proof::Disproven => (),
+                Proof::Unknown => analysis.unresolved.push(mv),
+            }
+        }
+        analysis.mating_moves.sort_by_key(|m| m.mate_len);
+        analysis
+    }
+
+    //最短の詰みの証明木。根は攻め方の最初の手
+    pub fn proof_tree(
+        &mut self,
+        board: &mut Bitboard,
+        history: &[u64],
+        max_depth: usize,
+    ) -> Option<ProofTree> {
+        self.start_call();
+        self.attacker = board.turn;
+        let Proof::Proven(_) = self.shortest_mate(board, history, max_depth) else {
+            return None;
+        };
+        self.seen = history.to_vec();
+        self.build_tree(board, history.last().copied(), self.proof_depth)
+            .pop()
+    }
+
+    //self.attackerが詰ませる最短の手数。見つかった詰みより短い手数に制限して解き直す
+    //詰んだ場合は、最短の詰みを証明した置換表をproof_depthとともに残す
+    fn shortest_mate(&mut self, board: &mut Bitboard, history: &[u64], max_depth: usize) -> Proof {
+        let mut best: Option<(usize, usize, HashMap<DfpnKey, DfpnEntry>)> = None;
+        let mut limit = max_depth.min(u8::MAX as usize);
+        loop {
+            match self.solve_within(board, history, limit) {
+                Some(true) => {
+                    let len = self.extract_pv(board, history, limit).len();
+                    best = Some((len, limit, std::mem::take(&mut self.tt)));
+                    //攻め方の手で終わるので、より短い詰みは2手短い
+                    if len < 3 {
+                        break;
+                    }
+                    limit = len - 2;
+                }
+                Some(false) => break,
+                None if best.is_none() => return Proof::Unknown,
+                //上限に達したら見つけた中で最短の詰みを返す(exhaustedで分かる)
+                None => break,
+            }
+        }
+        match best {
+            Some((len, limit, tt)) => {
+                self.tt = tt;
+                self.proof_depth = limit;
+                Proof::Proven(len)
+            }
+            None => Proof::Disproven,
+        }
+    }
+
+    //limit手以内に詰むか(ノード数の上限に達したらNone)
+    fn solve_within(
+        &mut self,
+        board: &mut Bitboard,
+        history: &[u64],
+        limit: usize,
+    ) -> Option<bool> {
+        self.tt.clear();
+        self.seen = history.to_vec();
+        if board.game_over() {
+            return Some(false);
+        }
+        let entry = self.mid(board, history.last().copied(), limit, INF, INF);
+        if entry.pn == 0 {
+            Some(true)
+        } else if entry.dn == 0 {
+            Some(false)
+        } else {
+            self.exhausted = true;
+            None
+        }
+    }
+
+    fn mid(
+        &mut self,
+        board: &mut Bitboard,
+        prev_hash: Option<u64>,
+        depth: usize,
+        th_pn: u32,
+        th_dn: u32,
+    ) -> DfpnEntry {
+        self.nodes += 1;
+        let hash = board.to_compression_bod();
+        let key = (hash, prev_hash, depth as u8);
+        if let Some(entry) = self.tt.get(&key).filter(|e| e.is_solved()) {
+            return *entry;
+        }
+        let is_or = board.turn == self.attacker;
+
+        if let Some(entry) = self.evaluate_without_moves(board, prev_hash, depth, is_or) {
+            self.tt.insert(key, entry);
+            return entry;
+        }
+
+        let moves: Vec<MoveBit> = if is_or {
+            generate_threat_moves(board, prev_hash)
+                .into_iter()
+                .collect()
+        } else {
+            board
+                .iter_legal_move()
+                .filter(|&mv| !board.check_illegal_move(mv, prev_hash))
+                .collect()
+        };
+        self.seen.push(hash);
+        let mut children: Vec<(MoveBit, DfpnEntry)> = moves
+            .into_iter()
+            .map(|mv| (mv, self.child_entry(board, mv, prev_hash, depth)))
+            .collect();
+
+        let result = loop {
+            let current = combine(&children, is_or);
+            if current.pn >= th_pn || current.dn >= th_dn || self.nodes >= self.node_limit {
+                break current;
+            }
+
+            //攻め方は証明数、受け方は反証数が最小の子を展開する
+            let number = |e: &DfpnEntry| if is_or { e.pn } else { e.dn };
+            let mut best = 0;
+            let mut second = INF;
+            for (i, (_, child)) in children.iter().enumerate() {
+                if number(child) < number(&children[best].1) {
+                    second = number(&children[best].1);
+                    best = i;
+                } else if i != best {
+                    second = second.min(number(child));
+                }
+            }
+            let (mv, child) = children[best];
+            let (child_th_pn, child_th_dn) = if is_or {
+                (
+                    th_pn.min(second.saturating_add(1)),
+                    (th_dn - current.dn).saturating_add(child.dn).min(INF),
+                )
+            } else {
+                (
+                    (th_pn - current.pn).saturating_add(child.pn).min(INF),
+                    th_dn.min(second.saturating_add(1)),
+                )
+            };
+
+            board
+                .apply_force_with_check_illegal_move(mv, prev_hash)
+                .expect("generated moves must be legal");
+            children[best].1 = self.mid(board, Some(hash), depth - 1, child_th_pn, child_th_dn);
+            board.undo_force(mv);
+        };
+        self.seen.pop();
+        self.tt.insert(key, result);
+        result
+    }
+
+    //手を生成せずに決まる局面
+    fn evaluate_without_moves(
+        &self,
+        board: &mut Bitboard,
+        prev_hash: Option<u64>,
+        depth: usize,
+        is_or: bool,
+    ) -> Option<DfpnEntry> {
+        if is_or {
+            if find_mate_in_one_move(board, prev_hash).is_some() {
+                return Some(DfpnEntry::proven(1));
+            }
+            //詰めろ・受け・勝ちの3手が指せない
+            if depth < 3 {
+                return Some(DfpnEntry::disproven());
+            }
+        } else {
+            //受け方が先に勝てる
+            if checkmate_in_one_move(board, prev_hash) {
+                return Some(DfpnEntry::disproven());
+            }
+            if depth < 2 {
+                return Some(DfpnEntry::disproven());
+            }
+        }
+        None
+    }
+
+    //mvを指した後の局面の値(決着・千日手・置換表)
+    fn child_entry(
+        &self,
+        board: &mut Bitboard,
+        mv: MoveBit,
+        prev_hash: Option<u64>,
+        depth: usize,
+    ) -> DfpnEntry {
+        let hash = board.to_compression_bod();
+        board
+            .apply_force_with_check_illegal_move(mv, prev_hash)
+            .expect("generated moves must be legal");
+        let child_hash = board.to_compression_bod();
+        let entry = match board.win_eval().value {
+            EvalValue::Win(winner) if winner as i8 == self.attacker => DfpnEntry::proven(0),
+            EvalValue::Win(_) | EvalValue::Draw => DfpnEntry::disproven(),
+            _ if self.seen.contains(&child_hash) => DfpnEntry {
+                by_repetition: true,
+                ..DfpnEntry::disproven()
+            },
+            _ => self
+                .tt
+                .get(&(child_hash, Some(hash), (depth - 1) as u8))
+                .filter(|e| !(e.dn == 0 && e.by_repetition))
+                .copied()
+                .unwrap_or(DfpnEntry::UNKNOWN),
+        };
+        board.undo_force(mv);
+        entry
+    }
+
+    //置換表で詰みが証明されている手と、その手から勝ちまでの手数
+    fn proven_moves(
+        &self,
+        board: &mut Bitboard,
+        prev_hash: Option<u64>,
+        depth: usize,
+    ) -> Vec<(MoveBit, usize)> {
+        let moves: Vec<MoveBit> = board
+            .iter_legal_move()
+            .filter(|&mv| !board.check_illegal_move(mv, prev_hash))
+            .collect();
+        moves
+            .into_iter()
+            .filter_map(|mv| {
+                let entry = self.child_entry(board, mv, prev_hash, depth);
+                (entry.pn == 0).then_some((mv, entry.mate_len as usize + 1))
+            })
+            .collect()
+    }
+
+    //証明済みの置換表をたどって手順を作る。攻め方は最短、受け方は最長の手を選ぶ
+    fn extract_pv(&mut self, board: &mut Bitboard, history: &[u64], limit: usize) -> Vec<MoveBit> {
+        self.seen = history.to_vec();
+        let mut prev_hash = history.last().copied();
+        let mut depth = limit;
+        let mut pv = Vec::new();
+        while !board.game_over() && depth > 0 {
+            let is_or = board.turn == self.attacker;
+            let hash = board.to_compression_bod();
+            let proven = self.proven_moves(board, prev_hash, depth).into_iter();
+            let best = if is_or {
+                proven.min_by_key(|&(_, len)| len)
+            } else {
+                proven.max_by_key(|&(_, len)| len)
+            };
+            let Some((mv, _)) = best else {
+                break;
+            };
+            board
+                .apply_force_with_check_illegal_move(mv, prev_hash)
+                .expect("pv moves must be legal");
+            self.seen.push(hash);
+            pv.push(mv);
+            prev_hash = Some(hash);
+            depth -= 1;
+        }
+        for &mv in pv.iter().rev() {
+            board.undo_force(mv);
+        }
+        pv
+    }
+
+    //攻め方は最短の1手、受け方はすべての手を展開する
+    fn build_tree(
+        &mut self,
+        board: &mut Bitboard,
+        prev_hash: Option<u64>,
+        depth: usize,
+    ) -> Vec<ProofTree> {
+        if board.game_over() || depth == 0 {
+            return Vec::new();
+        }
+        let hash = board.to_compression_bod();
+        let mut proven = self.proven_moves(board, prev_hash, depth);
+        if board.turn == self.attacker {
+            proven.sort_by_key(|&(_, len)| len);
+            proven.truncate(1);
+        } else {
+            proven.sort_by_key(|&(_, len)| std::cmp::Reverse(len));
+        }
+        self.seen.push(hash);
+        let tree = proven
+            .into_iter()
+            .map(|(mv, mate_len)| {
+                board
+                    .apply_force_with_check_illegal_move(mv, prev_hash)
+                    .expect("proven moves must be legal");
+                let replies = self.build_tree(board, Some(hash), depth - 1);
+                board.undo_force(mv);
+                ProofTree {
+                    mv,
+                    mate_len,
+                    replies,
+                }
+            })
+            .collect();
+        self.seen.pop();
+        tree
+    }
+}
+
+//子の値から局面の値を求める(攻め方: いずれかが詰めば詰み、受け方: すべて詰めば詰み)
+fn combine(children: &[(MoveBit, DfpnEntry)], is_or: bool) -> DfpnEntry {
+    if children.is_empty() {
+        //攻め方は詰めろの手がなければ不詰、受け方は指せる手がなければ負け
+        return if is_or {
+            DfpnEntry::disproven()
+        } else {
+            DfpnEntry::proven(0)
+        };
+    }
+    let entries = children.iter().map(|(_, e)| e);
+    let sum = |values: &mut dyn Iterator<Item = u32>| {
+        values.fold(0u32, |a, b| a.saturating_add(b)).min(INF)
+    };
+    if is_or {
+        let pn = entries.clone().map(|e| e.pn).min().unwrap();
+        let dn = sum(&mut entries.clone().map(|e| e.dn));
+        let mate_len = entries
+            .clone()
+            .filter(|e| e.pn == 0)
+            .map(|e| e.mate_len + 1)
+            .min();
+        DfpnEntry {
+            pn,
+            dn,
+            mate_len: mate_len.unwrap_or(0),
+            by_repetition: dn == 0 && entries.clone().any(|e| e.by_repetition),
+        }
+    } else {
+        let pn = sum(&mut entries.clone().map(|e| e.pn));
+        let dn = entries.clone().map(|e| e.dn).min().unwrap();
+        let mate_len = if pn == 0 {
+            entries.clone().map(|e| e.mate_len + 1).max().unwrap()
+        } else {
+            0
+        };
+        DfpnEntry {
+            pn,
+            dn,
+            mate_len,
+            by_repetition: dn == 0 && !entries.clone().any(|e| e.dn == 0 && !e.by_repetition),
+        }
+    }
+}
+
+#[test]
+fn test_dfpn_finds_shortest_mate() {
+    use crate::bitboard_console::board_from_moves;
+    use crate::game::Game;
+
+    let (mut board, history) = board_from_moves("S 0 4; S 0 1; S 4 3; S 3 0").unwrap();
+    let hash = board.to_compression_bod();
+    let MateResult::Mate(pv) = DfpnSolver::new(100_000).solve(&mut board, &history, 15) else {
+        panic!("mate not found");
+    };
+    assert_eq!(board.to_compression_bod(), hash);
+
+    //手順どおりに指すと攻め方が勝つ
+    let mut game = Game::from_position(board, history.clone());
+    for &mv in &pv {
+        game.apply(mv).unwrap();
+    }
+    assert!(game.board.game_over());
+    assert_eq!(game.board.win_turn(), board.turn as i16);
+
+    //それより短い手数では詰まない
+    let shorter = DfpnSolver::new(100_000).solve(&mut board, &history, pv.len() - 2);
+    assert_eq!(shorter, MateResult::NoMate);
+
+    //初期局面に短い詰みはない
+    let mut initial = Bitboard::new_initial();
+    assert_eq!(
+        DfpnSolver::new(100_000).solve(&mut initial, &[], 5),
+        MateResult::NoMate
+    );
+}
+
+#[test]
+fn test_proof_tree_covers_all_replies() {
+    use crate::bitboard_console::board_from_moves;
+    use crate::game::Game;
+
+    //攻め方の手は勝ちで終わり、受け方はすべての合法手が含まれている
+    fn verify(game: &Game, tree: &ProofTree, attacker: i8) {
+        let mut next = game.clone();
+        next.apply(tree.mv).unwrap();
+        if tree.replies.is_empty() {
+            assert!(next.board.game_over());
+            assert_eq!(next.board.win_turn(), attacker as i16);
+            return;
+        }
+        let mut replies: Vec<MoveBit> = tree.replies.iter().map(|r| r.mv).collect();
+        if next.board.turn == attacker {
+            assert_eq!(replies.len(), 1);
+        } else {
+            let mut legal = next.legal_moves();
+            legal.sort_by_key(|m| m.to_string());
+            replies.sort_by_key(|m| m.to_string());
+            assert_eq!(replies, legal);
+        }
+        for reply in &tree.replies {
+            assert!(reply.mate_len < tree.mate_len);
+            verify(&next, reply, attacker);
+        }
+    }
+
+    let (mut board, history) = board_from_moves(
+        "S 0 2; S 2 2; S 2 0; S 4 4; S 2 4; S 0 1; S 0 0; F 2 2 5; F 0 0 2; S 1 4",
+    )
+    .unwrap();
+    let mut solver = DfpnSolver::new(1_000_000);
+    let MateResult::Mate(pv) = solver.solve(&mut board, &history, 9) else {
+        panic!("mate not found");
+    };
+    let tree = solver.proof_tree(&mut board, &history, 9).unwrap();
+    assert_eq!(tree.mate_len, pv.len());
+    verify(
+        &Game::from_position(board, history.clone()),
+        &tree,
+        board.turn,
+    );
+
+    //最短の詰みは詰ませる手の先頭にある
+    let analysis = solver.analyze(&mut board, &history, 9);
+    assert!(analysis.unresolved.is_empty());
+    assert_eq!(analysis.mating_moves[0].mate_len, pv.len());
+    assert!(analysis.mating_moves.iter().any(|m| m.mv == pv[0]));
+}
+
+#[test]
+fn test_each_call_has_own_node_budget() {
+    use crate::bitboard_console::board_from_moves;
+
+    let (mut board, history) = board_from_moves(
+        "S 0 2; S 2 2; S 2 0; S 4 4; S 2 4; S 0 1; S 0 0; F 2 2 5; F 0 0 2; S 1 4",
+    )
+    .unwrap();
+    let mut probe = DfpnSolver::new(1_000_000);
+    probe.solve(&mut board, &history, 9);
+
+    //mateコマンドの--all --treeと同じく1つのsolverで続けて呼んでも、solve1回分の上限で足りる
+    let mut solver = DfpnSolver::new(probe.nodes());
+    let MateResult::Mate(pv) = solver.solve(&mut board, &history, 9) else {
+        panic!("mate not found");
+    };
+    let analysis = solver.analyze(&mut board, &history, 9);
+    assert!(analysis.mating_moves.iter().any(|m| m.mv == pv[0]));
+    let tree = solver.proof_tree(&mut board, &history, 9).unwrap();
+    assert_eq!(tree.mate_len, pv.len());
+    assert!(solver.nodes() > probe.nodes());
+
+    //上限で打ち切ったことが分かる
+    let mut small = DfpnSolver::new(10);
+    assert_eq!(small.solve(&mut board, &history, 9), MateResult::Unknown);
+    assert!(small.exhausted());
+}
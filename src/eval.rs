@@ -146,6 +146,7 @@ fn evaluate_reach(vidro: &mut Bitboard, prev_hash: Option<u64>) -> i16 {
     return 0;
 }
 
+use crate::search_params::SearchParams;
 use crate::snapshot::BoardSnapshot;
 use crate::snapshot_features::{BitIter, BoardSnapshotFeatures, NUM_FEATURES};
 use rayon::prelude::*;
@@ -166,9 +167,13 @@ pub struct GameResult {
 
 impl AiModel {
     pub fn rand_new() -> Self {
+        Self::rand_new_with_rng(&mut rand::rng())
+    }
+    //乱数生成器を指定する(シードを固定すれば同じ重みになる)
+    pub fn rand_new_with_rng(rng: &mut impl rand::Rng) -> Self {
         Self {
             weights: (0..NUM_FEATURES)
-                .map(|_| rand::random_range(-0.1f32..=0.1f32))
+                .map(|_| rng.random_range(-0.1f32..=0.1f32))
                 .collect::<Vec<f32>>(),
         }
     }
@@ -256,6 +261,16 @@ impl AiModel {
     pub fn weight_norm(&self) -> f32 {
         self.weights.iter().map(|w| w.powi(2)).sum::<f32>().sqrt()
     }
+
+    //探索で使う評価関数(手番側から見た評価値)
+    pub fn evaluator<'a>(
+        &'a self,
+        params: &'a SearchParams,
+    ) -> impl Fn(&BoardSnapshot) -> i16 + Sync + 'a {
+        move |snapshot: &BoardSnapshot| {
+            params.eval_from_logit(self.eval_score(snapshot.iter_feature_indices()))
+        }
+    }
 }
 
 pub fn sigmoid(x: f32) -> f32 {
@@ -0,0 +1,202 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+
+use crate::bitboard::{Bitboard, MoveBit};
+use crate::bitboard_console::BitboardConsole;
+use crate::eval_value::EvalValue;
+
+//対局の手数制限。これだけ指して決着がつかなければ引き分け
+pub const MAX_MOVES: usize = 100;
+
+//対局中の局面と、そこまでの経過
+#[derive(Clone)]
+pub struct Game {
+    pub board: Bitboard,
+    pub history: Vec<u64>,   //これまでに現れた局面(古い順、最後が直前の局面)
+    pub moves: Vec<MoveBit>, //この対局で指された手
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GameOutcome {
+    Win(i8),     //三目並べて勝ち。値は勝った側の手番(1: 先手, -1: 後手)
+    Forfeit(i8), //指せる手がない・反則手による負け。値は負けた側の手番
+    Draw,        //双方が同時に並んだ
+    Repetition,  //千日手
+    MoveLimit,
+}
+
+impl GameOutcome {
+    //先手から見た結果(勝ち1.0, 引き分け0.5, 負け0.0)
+    pub fn score_for_first(&self) -> f32 {
+        match self {
+            GameOutcome::Win(winner) => (*winner as f32 + 1.0) / 2.0,
+            GameOutcome::Forfeit(loser) => (1.0 - *loser as f32) / 2.0,
+            _ => 0.5,
+        }
+    }
+}
+
+impl std::fmt::Display for GameOutcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let side = |turn: i8| if turn == 1 { "先手" } else { "後手" };
+        match self {
+            GameOutcome::Win(winner) => write!(f, "勝者: {}", side(*winner)),
+            GameOutcome::Forfeit(loser) => write!(f, "{}の反則負け", side(*loser)),
+            GameOutcome::Draw => write!(f, "引き分け"),
+            GameOutcome::Repetition => write!(f, "千日手により引き分け"),
+            GameOutcome::MoveLimit => write!(f, "手数経過により引き分け"),
+        }
+    }
+}
+
+impl Game {
+    pub fn new() -> Self {
+        Self::from_position(Bitboard::new_initial(), Vec::new())
+    }
+
+    pub fn from_position(board: Bitboard, history: Vec<u64>) -> Self {
+        Self {
+            board,
+            history,
+            moves: Vec::new(),
+        }
+    }
+
+    pub fn prev_hash(&self) -> Option<u64> {
+        self.history.last().copied()
+    }
+
+    pub fn last_move(&self) -> Option<MoveBit> {
+        self.moves.last().copied()
+    }
+
+    //直前の局面に戻る手を除いた合法手
+    pub fn legal_moves(&self) -> Vec<MoveBit> {
+        let mut board = self.board;
+        let prev_hash = self.prev_hash();
+        self.board
+            .iter_legal_move()
+            .filter(|&mv| !board.check_illegal_move(mv, prev_hash))
+            .collect()
+    }
+
+    pub fn apply(&mut self, mv: MoveBit) -> Result<(), ()> {
+        let hash = self.board.to_compression_bod();
+        self.board
+            .apply_force_with_check_illegal_move(mv, self.prev_hash())?;
+        self.history.push(hash);
+        self.moves.push(mv);
+        Ok(())
+    }
+
+    //決着がついていれば結果を返す(手数制限は含まない)
+    pub fn outcome(&self) -> Option<GameOutcome> {
+        match self.board.win_eval().value {
+            EvalValue::Win(winner) => return Some(GameOutcome::Win(winner as i8)),
+            EvalValue::Draw => return Some(GameOutcome::Draw),
+            _ => (),
+        }
+        if self.history.contains(&self.board.to_compression_bod()) {
+            return Some(GameOutcome::Repetition);
+        }
+        None
+    }
+}
+
+//対局者。人間・ランダム・評価関数・探索などの指し方を同じように扱う
+pub trait Player: Send {
+    fn name(&self) -> String;
+    fn new_game(&mut self) {}
+    //合法手がある局面でだけ呼ばれる
+    fn choose_move(&mut self, game: &Game) -> MoveBit;
+    //どちらかの手が指された後に両方の対局者へ通知する
+    fn notify_move(&mut self, _game: &Game, _mv: MoveBit) {}
+    //相手の手番中に呼ばれる。相手が手を決めるとstopが立つので、それまでに戻ればよい
+    fn ponder(&mut self, _game: &Game, _stop: &Arc<AtomicBool>) {}
+}
+
+//players: [先手, 後手]。verboseなら局面と指し手を表示する
+pub fn play_game(
+    game: &mut Game,
+    players: [&mut dyn Player; 2],
+    max_moves: usize,
+    verbose: bool,
+) -> GameOutcome {
+    let [first, second] = players;
+    first.new_game();
+    second.new_game();
+    if verbose {
+        println!("先手: {}  後手: {}", first.name(), second.name());
+    }
+
+    let outcome = loop {
+        if verbose {
+            println!("\n--------------------------------");
+            println!("{}", game.board.to_string());
+            let relative = game.board.to_snapshot(game.prev_hash()).to_relative();
+            println!(
+                "white have: {}\nblack have: {}",
+                relative.p1_hand_piece, relative.p2_hand_piece
+            );
+        }
+        if let Some(outcome) = game.outcome() {
+            break outcome;
+        }
+        if game.moves.len() >= max_moves {
+            break GameOutcome::MoveLimit;
+        }
+        let turn = game.board.turn;
+        if game.legal_moves().is_empty() {
+            break GameOutcome::Forfeit(turn);
+        }
+
+        let (mover, waiting) = if turn == 1 {
+            (&mut *first, &mut *second)
+        } else {
+            (&mut *second, &mut *first)
+        };
+        let position: &Game = game;
+        let stop = Arc::new(AtomicBool::new(false));
+        let mv = thread::scope(|s| {
+            let pondering = s.spawn(|| waiting.ponder(position, &stop));
+            let mv = mover.choose_move(position);
+            stop.store(true, Ordering::Relaxed);
+            pondering.join().unwrap();
+            mv
+        });
+
+        if verbose {
+            println!("\n決定手: {}", mv.to_string());
+        }
+        if game.apply(mv).is_err() {
+            break GameOutcome::Forfeit(turn);
+        }
+        first.notify_move(game, mv);
+        second.notify_move(game, mv);
+    };
+
+    if verbose {
+        println!("ゲーム終了 {}", outcome);
+    }
+    outcome
+}
+
+#[test]
+fn test_play_random_game() {
+    use crate::players::RandomPlayer;
+
+    for _ in 0..20 {
+        let mut game = Game::new();
+        let outcome = play_game(&mut game, [&mut RandomPlayer, &mut RandomPlayer], 60, false);
+        assert_eq!(game.history.len(), game.moves.len());
+        match outcome {
+            GameOutcome::Win(_) => assert!(game.board.game_over()),
+            GameOutcome::MoveLimit => assert_eq!(game.moves.len(), 60),
+            GameOutcome::Repetition => {
+                assert!(game.history.contains(&game.board.to_compression_bod()))
+            }
+            _ => (),
+        }
+    }
+}
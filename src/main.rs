@@ -1,35 +1,62 @@
+mod bench;
 mod bitboard;
 mod bitboard_console;
 mod checkmate_search;
+mod dfpn;
 mod eval;
 mod eval_value;
+mod game;
+mod mcts;
+mod move_ordering;
+mod opening_book;
+mod players;
 mod pre_train;
 mod random_state_generator;
 mod search;
+mod search_observer;
+mod search_params;
+mod search_trace;
 mod self_match;
+mod skill;
 mod snapshot;
 mod util;
 
 mod snapshot_features;
-use bitboard::{Bitboard, MoveBit};
-use bitboard_console::BitboardConsole;
-use eval_value::EvalValue;
+mod spsa;
+mod symmetry;
+mod transposition_table;
+use bitboard_console::{BitboardConsole, board_from_moves};
 
-use clap::{Parser, Subcommand};
-use lru::LruCache;
+use clap::{Args, Parser, Subcommand, ValueEnum};
 use pre_train::pre_train_with_manual_eval;
-use rand::seq::IndexedRandom;
-use search::mtd_f;
-use std::num::NonZeroUsize;
+use serde_json::json;
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-use crate::bitboard::MoveList;
+use crate::bench::{BENCH_POSITIONS, run_bench};
+use crate::bitboard::MoveBit;
+use crate::checkmate_search::{
+    ThreatSearchOptions, ThreatSearchResult, generate_brinkmate_moves, threat_space_search,
+};
+use crate::dfpn::{DfpnSolver, MateResult};
 use crate::eval::{AiModel, sigmoid};
-use crate::search::{EVAL_VALUE_MALTIPLIER, find_best_move};
+use crate::game::{Game, GameOutcome, MAX_MOVES, Player, play_game};
+use crate::mcts::{Mcts, MctsOptions};
+use crate::opening_book::{BookBuildSettings, BookOptions, OpeningBook, build_book_from_search};
+use crate::players::{
+    GreedyPlayer, HumanPlayer, MctsPlayer, RandomPlayer, SearchPlayer, SoftmaxPlayer,
+};
+use crate::random_state_generator::random_state_generator;
+use crate::search::{SearchAlgorithm, SearchOptions, find_best_move};
+use crate::search_observer::{ConsoleObserver, JsonObserver, SearchObserver, SilentObserver};
+use crate::search_params::SearchParams;
+use crate::search_trace::SearchTrace;
 use crate::self_match::generate_self_play_data;
-use crate::snapshot::BoardSnapshot;
-use crate::snapshot_features::{BoardSnapshotFeatures, NUM_FEATURES};
-use crate::util::{load_model, save_model};
+use crate::skill::{SKILL_LEVELS, Skill, elo_from_score};
+use crate::snapshot_features::NUM_FEATURES;
+use crate::spsa::{SpsaSettings, spsa_tune};
+use crate::transposition_table::TranspositionTable;
+use crate::util::{cache_version, load_model, save_model};
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
@@ -38,6 +65,202 @@ struct Cli {
     command: Commands,
 }
 
+//探索を行うコマンドで共通の設定
+#[derive(Args)]
+struct SearchArgs {
+    #[arg(short, long, default_value_t = 5)]
+    depth: usize,
+
+    //置換表のサイズ(MB)
+    #[arg(long, default_value_t = 64)]
+    hash: usize,
+
+    //探索スレッド数
+    #[arg(short, long, default_value_t = 1)]
+    threads: usize,
+
+    //1手あたりの思考時間(ミリ秒)。指定しない場合は深さのみで打ち切る
+    #[arg(long)]
+    movetime: Option<u64>,
+
+    //表示する読み筋の数
+    #[arg(long, default_value_t = 1)]
+    multipv: usize,
+
+    //アスピレーションウィンドウの初期幅(0で無効)
+    #[arg(long, default_value_t = 100)]
+    aspiration: i16,
+
+    //静止探索で延長する最大手数
+    #[arg(long, default_value_t = 4)]
+    qdepth: usize,
+
+    //ルートからの探索アルゴリズム
+    #[arg(long, value_enum, default_value_t = SearchAlgorithm::Pvs)]
+    algorithm: SearchAlgorithm,
+
+    //探索パラメータの設定ファイル(JSON)。指定しない場合は既定値
+    #[arg(long)]
+    config: Option<String>,
+
+    //枝刈りを個別に有効にする(設定ファイルより優先)
+    #[arg(long)]
+    null_move: bool,
+    #[arg(long)]
+    futility: bool,
+    #[arg(long)]
+    razoring: bool,
+    //延長などを個別に無効にする(設定ファイルより優先)
+    #[arg(long)]
+    no_brinkmate: bool,
+    //df-pnの詰み探索を有効にする(設定ファイルより優先)
+    #[arg(long)]
+    dfpn: bool,
+
+    //同じ局面なら常に同じ結果になるように探索する(1スレッド、時間制限なし、毎回空の置換表)
+    #[arg(long)]
+    deterministic: bool,
+
+    //定跡ファイル。指定すると序盤は定跡の手を探索せずに指す
+    #[arg(long)]
+    book: Option<String>,
+
+    //対局の何手目まで定跡を使うか
+    #[arg(long, default_value_t = 8)]
+    book_depth: usize,
+
+    //定跡手の選び方のばらつき(0で最も重みが大きい手、1で重みに比例した確率)
+    #[arg(long, default_value_t = 0.5)]
+    book_randomness: f32,
+
+    //置換表を保存するファイル。あれば探索の前に読み込み、探索の後に保存する
+    #[arg(long)]
+    cache: Option<String>,
+
+    //置換表ファイルに保存するエントリの最小の残り深さ
+    #[arg(long, default_value_t = 4)]
+    cache_min_depth: u8,
+
+    //引き分けを避ける度合い(評価値)。正なら千日手・手数制限の引き分けを避け、負なら目指す
+    #[arg(long, default_value_t = 0, allow_negative_numbers = true)]
+    contempt: i16,
+
+    //棋力のレベル(0が最も弱い)。指定しない場合は制限なし
+    #[arg(long)]
+    skill: Option<usize>,
+}
+
+//置換表ファイルの設定
+struct CacheSettings {
+    path: String,
+    min_depth: u8,
+}
+
+impl SearchArgs {
+    fn to_options(&self) -> Result<SearchOptions, String> {
+        let mut params = match &self.config {
+            Some(path) => SearchParams::load(path)
+                .map_err(|e| format!("設定ファイルを読み込めません: {}: {}", path, e))?,
+            None => SearchParams::default(),
+        };
+        params.null_move |= self.null_move;
+        params.futility |= self.futility;
+        params.razoring |= self.razoring;
+        params.brinkmate_extension &= !self.no_brinkmate;
+        params.brinkmate_eval &= !self.no_brinkmate;
+        params.dfpn |= self.dfpn;
+        let book = match &self.book {
+            Some(path) => Some(BookOptions {
+                book: Arc::new(
+                    OpeningBook::load(path)
+                        .map_err(|e| format!("定跡ファイルを読み込めません: {}: {}", path, e))?,
+                ),
+                depth: self.book_depth,
+                randomness: self.book_randomness.max(0.0),
+            }),
+            None => None,
+        };
+        let skill = match self.skill {
+            Some(level) => Some(Skill::from_level(level).ok_or(format!(
+                "スキルレベルは0から{}で指定してください",
+                SKILL_LEVELS.len() - 1
+            ))?),
+            None => None,
+        };
+        //どちらも探索の前に置換表を消すので、読み込んだ置換表が使われない
+        if self.cache.is_some() && self.deterministic {
+            return Err("--cacheと--deterministicは同時に指定できません".to_string());
+        }
+        if self.cache.is_some() && skill.is_some() {
+            return Err("--cacheと--skillは同時に指定できません".to_string());
+        }
+        Ok(SearchOptions {
+            depth: self.depth,
+            threads: self.threads.max(1),
+            time_limit: self.movetime.map(Duration::from_millis),
+            multi_pv: self.multipv.max(1),
+            aspiration_window: self.aspiration.max(0),
+            quiescence_depth: self.qdepth,
+            algorithm: self.algorithm,
+            params,
+            stop_signal: None,
+            deterministic: self.deterministic,
+            trace: None,
+            book,
+            contempt: self.contempt,
+            moves_left: None,
+            skill,
+        })
+    }
+
+    fn cache_settings(&self) -> Option<CacheSettings> {
+        self.cache.as_ref().map(|path| CacheSettings {
+            path: path.clone(),
+            min_depth: self.cache_min_depth,
+        })
+    }
+}
+
+//対局で使うAIの探索方法
+#[derive(Clone, Copy, ValueEnum)]
+enum Engine {
+    #[value(name = "alphabeta")]
+    AlphaBeta,
+    //AiModelを方策・価値に使うモンテカルロ木探索(--playouts, --threads, --movetimeを使う)
+    Mcts,
+}
+
+//matchコマンドの対局者
+#[derive(Clone, Copy, ValueEnum)]
+enum PlayerKind {
+    Human,
+    Random,
+    //1手先の局面の評価が最も良い手
+    Greedy,
+    //浅い探索の評価のsoftmaxで選ぶ(--temperature)
+    Softmax,
+    Search,
+    Mcts,
+}
+
+//探索の進捗・結果の出力形式
+#[derive(Clone, Copy, ValueEnum)]
+enum OutputFormat {
+    Console,
+    //1行1イベントのJSON
+    Json,
+    //進捗を表示せず結果のみ
+    Silent,
+}
+
+//探索木の出力形式
+#[derive(Clone, Copy, ValueEnum)]
+enum TraceFormat {
+    Json,
+    //Graphviz
+    Dot,
+}
+
 #[derive(Subcommand)]
 enum Commands {
     Train {
@@ -46,14 +269,204 @@ enum Commands {
 
         #[arg(short, long, default_value_t = 320)]
         batch_size: usize,
+
+        //自己対局の乱数のシード。指定すると同じモデルから同じ対局データを生成する
+        #[arg(long)]
+        seed: Option<u64>,
     },
     Play {
-        #[arg(short, long, default_value_t = 5)]
-        depth: usize,
-
         //先手1 後手0
         #[arg(short, long, default_value_t = 1)]
         human_turn: i8,
+
+        //人間の手番中に予想手を先読みしない
+        #[arg(long)]
+        no_ponder: bool,
+
+        #[arg(long, value_enum, default_value_t = Engine::AlphaBeta)]
+        engine: Engine,
+
+        //MCTSで1手あたりに行うプレイアウト数
+        #[arg(long, default_value_t = 10000)]
+        playouts: usize,
+
+        #[command(flatten)]
+        search: SearchArgs,
+    },
+    Analyze {
+        //解析する局面(初期局面からの手順を ; 区切りで指定 例: "S 2 2; S 0 0")
+        #[arg(short, long, default_value = "")]
+        moves: String,
+
+        #[arg(short, long, value_enum, default_value_t = OutputFormat::Console)]
+        output: OutputFormat,
+
+        #[command(flatten)]
+        search: SearchArgs,
+    },
+    //手番側の詰みをdf-pnで探す
+    Mate {
+        //初期局面からの手順(; 区切り)
+        #[arg(short, long, default_value = "")]
+        moves: String,
+
+        //何手以内の詰みを探すか
+        #[arg(short, long, default_value_t = 15)]
+        depth: usize,
+
+        //探索するノード数の上限
+        #[arg(short, long, default_value_t = 1_000_000)]
+        nodes: usize,
+
+        //詰ませる手をすべて表示する
+        #[arg(long)]
+        all: bool,
+
+        //受け方のすべての応手を含む証明木を表示する
+        #[arg(long)]
+        tree: bool,
+
+        #[arg(short, long, value_enum, default_value_t = OutputFormat::Console)]
+        output: OutputFormat,
+    },
+    //手番側の詰めろの連続による勝ちを脅威空間探索で探す
+    Tss {
+        //初期局面からの手順(; 区切り)
+        #[arg(short, long, default_value = "")]
+        moves: String,
+
+        //何手以内の勝ちを探すか
+        #[arg(short, long, default_value_t = 31)]
+        depth: usize,
+
+        //探索するノード数の上限
+        #[arg(short, long, default_value_t = 1_000_000)]
+        nodes: usize,
+    },
+    //1つの局面の探索木(窓・評価値・置換表・枝刈り)を記録して出力する。探索は再現可能な設定で行う
+    Trace {
+        //初期局面からの手順(; 区切り)
+        #[arg(short, long, default_value = "")]
+        moves: String,
+
+        //ルートから何手目までのノードを記録するか
+        #[arg(long, default_value_t = 3)]
+        max_ply: usize,
+
+        //記録するノード数の上限
+        #[arg(long, default_value_t = 10_000)]
+        max_nodes: usize,
+
+        #[arg(short, long, value_enum, default_value_t = TraceFormat::Json)]
+        format: TraceFormat,
+
+        //出力先のファイル
+        #[arg(long)]
+        out: String,
+
+        #[command(flatten)]
+        search: SearchArgs,
+    },
+    //探索で序盤を展開して定跡を作り、定跡を使った自己対局の結果を加える。ファイルがあれば追記する
+    Book {
+        #[arg(long, default_value = "book.json")]
+        file: String,
+
+        //初期局面から何手目までの局面を登録するか
+        #[arg(long, default_value_t = 4)]
+        ply: usize,
+
+        //1つの局面で登録する手の数
+        #[arg(long, default_value_t = 3)]
+        width: usize,
+
+        //最善手との評価値の差がこれ以内の手だけ登録する
+        #[arg(long, default_value_t = 50)]
+        margin: i16,
+
+        //結果を定跡に加える自己対局の数
+        #[arg(long, default_value_t = 0)]
+        games: usize,
+
+        #[command(flatten)]
+        search: SearchArgs,
+    },
+    //固定の局面を再現可能な設定で探索し、結果のシグネチャを表示する(探索の挙動が変わったかの確認用)
+    Bench {
+        #[command(flatten)]
+        search: SearchArgs,
+    },
+    //探索パラメータをSPSA(エンジン同士の対局)で調整する。--configの値から始める
+    Tune {
+        #[arg(short, long, default_value_t = 200)]
+        iterations: usize,
+
+        //1回の更新で行う対局の組数(先後入れ替えで2局ずつ)
+        #[arg(long, default_value_t = 8)]
+        pairs: usize,
+
+        //開始局面を作るためのランダムな手数
+        #[arg(long, default_value_t = 4)]
+        random_moves: usize,
+
+        //調整結果の出力先
+        #[arg(short, long, default_value = "search_params.json")]
+        output: String,
+
+        #[command(flatten)]
+        search: SearchArgs,
+    },
+    //スキルレベルごとに制限なしの探索(--skill以外の設定)と対局させ、勝ち点からレーティング差を測る
+    Calibrate {
+        //測るレベル。指定しない場合はすべてのレベル
+        #[arg(long)]
+        level: Option<usize>,
+
+        //対局の組数(先後入れ替えで2局ずつ)
+        #[arg(short, long, default_value_t = 10)]
+        pairs: usize,
+
+        //開始局面を作るためのランダムな手数
+        #[arg(long, default_value_t = 4)]
+        random_moves: usize,
+
+        #[command(flatten)]
+        search: SearchArgs,
+    },
+    //2つの対局者を対局させる。同じ開始局面で先後を入れ替えて2局ずつ指す
+    Match {
+        #[arg(long, value_enum)]
+        first: PlayerKind,
+
+        #[arg(long, value_enum)]
+        second: PlayerKind,
+
+        //対局の組数
+        #[arg(short, long, default_value_t = 5)]
+        pairs: usize,
+
+        //開始局面を作るためのランダムな手数
+        #[arg(long, default_value_t = 4)]
+        random_moves: usize,
+
+        //softmaxの温度
+        #[arg(long, default_value_t = 0.5)]
+        temperature: f32,
+
+        //MCTSで1手あたりに行うプレイアウト数
+        #[arg(long, default_value_t = 10000)]
+        playouts: usize,
+
+        //局面と指し手を表示する
+        #[arg(short, long)]
+        verbose: bool,
+
+        //secondの探索のcontempt。指定しない場合は--contemptと同じ
+        #[arg(long, allow_negative_numbers = true)]
+        second_contempt: Option<i16>,
+
+        #[command(flatten)]
+        search: SearchArgs,
     },
 }
 
@@ -61,16 +474,178 @@ fn main() {
     let cli = Cli::parse();
 
     match &cli.command {
-        &Commands::Train { epochs, batch_size } => {
-            train_mode(epochs, batch_size);
-        }
-        &Commands::Play { depth, human_turn } => {
-            play_mode(depth, human_turn);
+        &Commands::Train {
+            epochs,
+            batch_size,
+            seed,
+        } => {
+            train_mode(epochs, batch_size, seed);
         }
+        Commands::Play {
+            human_turn,
+            no_ponder,
+            engine,
+            playouts,
+            search,
+        } => match search.to_options() {
+            Ok(options) => {
+                let mcts_options = match engine {
+                    Engine::AlphaBeta => None,
+                    Engine::Mcts => Some(MctsOptions {
+                        playouts: *playouts,
+                        threads: options.threads,
+                        time_limit: options.time_limit,
+                        ..Default::default()
+                    }),
+                };
+                play_mode(
+                    &options,
+                    mcts_options,
+                    *human_turn,
+                    search.hash,
+                    search.cache_settings(),
+                    !*no_ponder,
+                )
+            }
+            Err(e) => println!("{}", e),
+        },
+        Commands::Analyze {
+            moves,
+            output,
+            search,
+        } => match search.to_options() {
+            Ok(options) => analyze_mode(
+                &options,
+                moves,
+                search.hash,
+                search.cache_settings(),
+                *output,
+            ),
+            Err(e) => println!("{}", e),
+        },
+        Commands::Mate {
+            moves,
+            depth,
+            nodes,
+            all,
+            tree,
+            output,
+        } => mate_mode(moves, *depth, *nodes, *all, *tree, *output),
+        Commands::Tss {
+            moves,
+            depth,
+            nodes,
+        } => tss_mode(moves, *depth, *nodes),
+        Commands::Trace {
+            moves,
+            max_ply,
+            max_nodes,
+            format,
+            out,
+            search,
+        } => match search.to_options() {
+            Ok(options) => trace_mode(
+                &options,
+                moves,
+                search.hash,
+                SearchTrace::new(*max_ply, *max_nodes),
+                *format,
+                out,
+            ),
+            Err(e) => println!("{}", e),
+        },
+        Commands::Book {
+            file,
+            ply,
+            width,
+            margin,
+            games,
+            search,
+        } => match search.to_options() {
+            Ok(options) => {
+                let settings = BookBuildSettings {
+                    max_ply: *ply,
+                    width: *width,
+                    margin: (*margin).max(0),
+                };
+                book_mode(&options, &settings, file, *games, search.hash);
+            }
+            Err(e) => println!("{}", e),
+        },
+        Commands::Bench { search } => match search.to_options() {
+            Ok(options) => bench_mode(&options, search.hash),
+            Err(e) => println!("{}", e),
+        },
+        Commands::Tune {
+            iterations,
+            pairs,
+            random_moves,
+            output,
+            search,
+        } => match search.to_options() {
+            Ok(options) => {
+                let settings = SpsaSettings {
+                    iterations: *iterations,
+                    pairs_per_iteration: (*pairs).max(1),
+                    random_opening_moves: *random_moves,
+                    output_path: output.clone(),
+                };
+                tune_mode(&options, &settings);
+            }
+            Err(e) => println!("{}", e),
+        },
+        Commands::Calibrate {
+            level,
+            pairs,
+            random_moves,
+            search,
+        } => match search.to_options() {
+            Ok(options) => {
+                let levels: Vec<Skill> = match level {
+                    Some(level) => Skill::from_level(*level).into_iter().collect(),
+                    None => SKILL_LEVELS.to_vec(),
+                };
+                calibrate_mode(&options, &levels, *pairs, *random_moves, search.hash);
+            }
+            Err(e) => println!("{}", e),
+        },
+        Commands::Match {
+            first,
+            second,
+            pairs,
+            random_moves,
+            temperature,
+            playouts,
+            verbose,
+            second_contempt,
+            search,
+        } => match search.to_options() {
+            Ok(options) => {
+                let settings = MatchSettings {
+                    players: [*first, *second],
+                    pairs: *pairs,
+                    random_opening_moves: *random_moves,
+                    temperature: *temperature,
+                    mcts: MctsOptions {
+                        playouts: *playouts,
+                        threads: options.threads,
+                        time_limit: options.time_limit,
+                        ..Default::default()
+                    },
+                    verbose: *verbose,
+                    contempt: [
+                        options.contempt,
+                        second_contempt.unwrap_or(options.contempt),
+                    ],
+                };
+                match_mode(&options, &settings, search.hash);
+            }
+            Err(e) => println!("{}", e),
+        },
     }
 }
 
-fn train_mode(epochs: usize, batch_size: usize) {
+fn train_mode(epochs: usize, batch_size: usize, seed: Option<u64>) {
     const RANDOM_MOVES_UNTIL: usize = 2;
 
     println!("NUM_FEATURES: {}", NUM_FEATURES);
@@ -111,7 +686,13 @@ fn train_mode(epochs: usize, batch_size: usize) {
         };
 
         // 自己対局
-        let games = generate_self_play_data(RANDOM_MOVES_UNTIL, &ai_ctx, opponent_pool, batch_size);
+        let games = generate_self_play_data(
+            RANDOM_MOVES_UNTIL,
+            &ai_ctx,
+            opponent_pool,
+            batch_size,
+            seed.map(|seed| seed.wrapping_add((epoch * batch_size) as u64)),
+        );
 
         let weight_norm = ai_ctx.weight_norm();
 
@@ -164,129 +745,628 @@ fn train_mode(epochs: usize, batch_size: usize) {
     println!("学習完了");
 }
 
-fn play_mode(depth: usize, human_turn: i8) {
+fn load_play_model() -> Option<AiModel> {
     let load_path = "model_latest.bin";
 
     if !std::path::Path::new(load_path).exists() {
         println!("load_path:{} i not exists. ", load_path);
-        return;
+        return None;
     }
 
     let Ok(ai_ctx) = load_model(load_path) else {
         println!("Failed load model file");
+        return None;
+    };
+    Some(ai_ctx)
+}
+
+fn tune_mode(options: &SearchOptions, settings: &SpsaSettings) {
+    let Some(ai_ctx) = load_play_model() else {
         return;
     };
+    let tuned = spsa_tune(options, &ai_ctx, settings);
+    println!("調整完了: {}", settings.output_path);
+    println!("{:#?}", tuned);
+}
+
+struct MatchSettings {
+    players: [PlayerKind; 2],
+    pairs: usize,
+    random_opening_moves: usize,
+    temperature: f32,
+    mcts: MctsOptions,
+    verbose: bool,
+    contempt: [i16; 2], //[first, second]の探索のcontempt
+}
 
-    let evaluate = |snapshot: &BoardSnapshot| {
-        let z = ai_ctx.eval_score(snapshot.iter_feature_indices());
-        ((z * EVAL_VALUE_MALTIPLIER) as i16).clamp(-29000, 29000)
+fn match_mode(options: &SearchOptions, settings: &MatchSettings, hash_mb: usize) {
+    let Some(ai_ctx) = load_play_model() else {
+        return;
+    };
+    let evaluate = ai_ctx.evaluator(&options.params);
+    let observer: Box<dyn SearchObserver> = if settings.verbose {
+        Box::new(ConsoleObserver::new(options.params.eval_multiplier))
+    } else {
+        Box::new(SilentObserver)
     };
+    let tts = [
+        TranspositionTable::new(hash_mb),
+        TranspositionTable::new(hash_mb),
+    ];
 
-    let tt = Arc::new(Mutex::new(LruCache::new(
-        NonZeroUsize::new(100_000).unwrap(),
-    )));
+    let mut players: [Box<dyn Player>; 2] = [0, 1].map(|i| -> Box<dyn Player> {
+        match settings.players[i] {
+            PlayerKind::Human => Box::new(HumanPlayer),
+            PlayerKind::Random => Box::new(RandomPlayer),
+            PlayerKind::Greedy => Box::new(GreedyPlayer { model: &ai_ctx }),
+            PlayerKind::Softmax => Box::new(SoftmaxPlayer {
+                model: &ai_ctx,
+                temperature: settings.temperature,
+            }),
+            PlayerKind::Search => {
+                let options = SearchOptions {
+                    contempt: settings.contempt[i],
+                    ..options.clone()
+                };
+                let mut player = SearchPlayer::new(options, &tts[i], &evaluate, observer.as_ref());
+                player.verbose = settings.verbose;
+                player.max_moves = Some(MAX_MOVES);
+                Box::new(player)
+            }
+            PlayerKind::Mcts => {
+                let mut player = MctsPlayer::new(Mcts::new(&ai_ctx, settings.mcts.clone()));
+                player.verbose = settings.verbose;
+                Box::new(player)
+            }
+        }
+    });
+    let names: Vec<String> = players.iter().map(|p| p.name()).collect();
 
-    loop {
-        let mut vidro = Bitboard::new_initial();
+    let [a, b] = &mut players;
+    let results = play_pairs(
+        [a.as_mut(), b.as_mut()],
+        settings.pairs,
+        settings.random_opening_moves,
+        settings.verbose,
+        |i, swap, game, outcome| {
+            println!(
+                "game {:3}: 先手 {} / 後手 {}: {} ({}手)",
+                i + 1,
+                names[usize::from(swap)],
+                names[usize::from(!swap)],
+                outcome,
+                game.moves.len()
+            );
+        },
+    );
+    println!(
+        "{} vs {}: {}勝 {}分 {}敗 (スコア {:.3})",
+        names[0],
+        names[1],
+        results.wins,
+        results.draws,
+        results.losses,
+        results.score()
+    );
+}
 
-        let mut move_count = 0;
-        let mut prev_hash: Option<u64> = None;
-        const MAX_MOVES: usize = 100;
-        const RANDOM_MOVES_UNTIL: usize = 0;
+//players[0]から見た対局結果
+#[derive(Default)]
+struct MatchResults {
+    wins: usize,
+    draws: usize,
+    losses: usize,
+}
 
-        loop {
-            println!("\n--------------------------------");
-            println!("{}", vidro.to_string());
+impl MatchResults {
+    //勝ち点の割合(勝ち1, 引き分け0.5)
+    fn score(&self) -> f32 {
+        let games = (self.wins + self.draws + self.losses).max(1);
+        (self.wins as f32 + self.draws as f32 * 0.5) / games as f32
+    }
+}
 
-            let relative = vidro.to_snapshot(prev_hash).to_relative();
-            // print_u64("relative white", relative.p1);
-            // print_u64("relative black", relative.p2);
+//ランダムな開始局面ごとに先後を入れ替えて2局ずつ指す。
+//on_gameは各対局の後に(対局の番号, players[0]が後手だったか, 対局, 結果)で呼ぶ
+fn play_pairs(
+    players: [&mut dyn Player; 2],
+    pairs: usize,
+    random_opening_moves: usize,
+    verbose: bool,
+    mut on_game: impl FnMut(usize, bool, &Game, GameOutcome),
+) -> MatchResults {
+    let [a, b] = players;
+    let mut results = MatchResults::default();
+    for pair in 0..pairs {
+        let (board, prev_hash) = random_state_generator(random_opening_moves);
+        for swap in [false, true] {
+            let mut game = Game::from_position(board, prev_hash.into_iter().collect());
+            let order: [&mut dyn Player; 2] = if swap {
+                [&mut *b, &mut *a]
+            } else {
+                [&mut *a, &mut *b]
+            };
+            let outcome = play_game(&mut game, order, MAX_MOVES, verbose);
+            let score = if swap {
+                1.0 - outcome.score_for_first()
+            } else {
+                outcome.score_for_first()
+            };
+            if score > 0.5 {
+                results.wins += 1;
+            } else if score < 0.5 {
+                results.losses += 1;
+            } else {
+                results.draws += 1;
+            }
+            on_game(pair * 2 + usize::from(swap), swap, &game, outcome);
+        }
+    }
+    results
+}
+
+fn calibrate_mode(
+    options: &SearchOptions,
+    levels: &[Skill],
+    pairs: usize,
+    random_moves: usize,
+    hash_mb: usize,
+) {
+    let Some(ai_ctx) = load_play_model() else {
+        return;
+    };
+    let evaluate = ai_ctx.evaluator(&options.params);
+    let tts = [
+        TranspositionTable::new(hash_mb),
+        TranspositionTable::new(hash_mb),
+    ];
+    let baseline = SearchOptions {
+        skill: None,
+        ..options.clone()
+    };
+
+    for skill in levels {
+        let limited = SearchOptions {
+            skill: Some(*skill),
+            ..options.clone()
+        };
+        //置換表は対局者ごとに分ける
+        let mut players = [0, 1].map(|i| {
+            let options = if i == 0 { &limited } else { &baseline };
+            let mut player =
+                SearchPlayer::new(options.clone(), &tts[i], &evaluate, &SilentObserver);
+            player.max_moves = Some(MAX_MOVES);
+            player
+        });
+
+        let [a, b] = &mut players;
+        let results = play_pairs([a, b], pairs, random_moves, false, |_, _, _, _| {});
+        let score = results.score();
+        println!(
+            "レベル {}: {}勝 {}分 {}敗 (スコア {:.3}) レーティング差 {:+.0} (目安 {:+})",
+            skill.level,
+            results.wins,
+            results.draws,
+            results.losses,
+            score,
+            elo_from_score(score),
+            skill.elo
+        );
+    }
+}
+
+fn mate_mode(
+    moves: &str,
+    max_depth: usize,
+    max_nodes: usize,
+    all: bool,
+    tree: bool,
+    output: OutputFormat,
+) {
+    let (mut vidro, history) = match board_from_moves(moves) {
+        Ok(position) => position,
+        Err(e) => {
+            println!("{}", e);
+            return;
+        }
+    };
+    let is_json = matches!(output, OutputFormat::Json);
+    if matches!(output, OutputFormat::Console) {
+        println!("{}", vidro.to_string());
+    }
+    let pv_to_strings = |pv: &[MoveBit]| pv.iter().map(|m| m.to_string()).collect::<Vec<_>>();
+
+    let start = Instant::now();
+    let mut solver = DfpnSolver::new(max_nodes);
+    let result = solver.solve(&mut vidro, &history, max_depth);
+    let analysis = all.then(|| solver.analyze(&mut vidro, &history, max_depth));
+    let proof_tree = if tree {
+        solver.proof_tree(&mut vidro, &history, max_depth)
+    } else {
+        None
+    };
+    let elapsed = start.elapsed();
+
+    if is_json {
+        let mut value = json!({
+            "result": match result {
+                MateResult::Mate(_) => "mate",
+                MateResult::NoMate => "no_mate",
+                MateResult::Unknown => "unknown",
+            },
+            "nodes": solver.nodes(),
+            "exhausted": solver.exhausted(),
+            "elapsed_ms": elapsed.as_millis() as u64,
+        });
+        if let MateResult::Mate(pv) = &result {
+            value["mate_len"] = json!(pv.len());
+            value["pv"] = json!(pv_to_strings(pv));
+        }
+        if let Some(analysis) = &analysis {
+            value["mating_moves"] = analysis
+                .mating_moves
+                .iter()
+                .map(|m| json!({ "move": m.mv.to_string(), "mate_len": m.mate_len }))
+                .collect();
+            value["unresolved"] = json!(pv_to_strings(&analysis.unresolved));
+        }
+        if let Some(proof_tree) = &proof_tree {
+            value["tree"] = proof_tree.to_json();
+        }
+        println!("{}", value);
+        return;
+    }
+
+    match &result {
+        MateResult::Mate(pv) => println!("{}手詰め: {}", pv.len(), pv_to_strings(pv).join(" ")),
+        MateResult::NoMate => println!("{}手以内の詰みはありません", max_depth),
+        MateResult::Unknown => println!("ノード数の上限に達しました"),
+    }
+    if let Some(analysis) = &analysis {
+        println!("詰ませる手: {}", analysis.mating_moves.len());
+        for m in &analysis.mating_moves {
+            println!("  {:<12} {}手", m.mv.to_string(), m.mate_len);
+        }
+        if !analysis.unresolved.is_empty() {
             println!(
-                "white have: {}\nblack have: {}",
-                relative.p1_hand_piece, relative.p2_hand_piece
+                "判定できなかった手: {}",
+                pv_to_strings(&analysis.unresolved).join(" ")
             );
+        }
+    }
+    if let Some(proof_tree) = &proof_tree {
+        println!("証明木 (ノード数: {})", proof_tree.size());
+        print!("{}", proof_tree.render());
+    }
+    if solver.exhausted() && !matches!(result, MateResult::Unknown) {
+        println!("ノード数の上限で打ち切った探索があります(詰みの手数は最短とは限りません)");
+    }
+    println!(
+        "ノード数: {} 時間: {:.2}s",
+        solver.nodes(),
+        elapsed.as_secs_f32()
+    );
+}
 
-            {
-                let win_eval_result = vidro.win_eval();
-                if win_eval_result.evaluated {
-                    match win_eval_result.value {
-                        EvalValue::Win(v) => {
-                            println!("ゲーム終了 勝者: {}", if v == 1 { "先手" } else { "後手" });
-                        }
-                        EvalValue::Draw => {
-                            println!("ゲーム終了 引き分け");
-                        }
-                        _ => (),
-                    }
-                    break;
-                }
-            }
-            if move_count >= MAX_MOVES {
-                println!("ゲーム終了 {}手経過により引き分け", MAX_MOVES);
-                break;
-            }
+fn tss_mode(moves: &str, max_depth: usize, max_nodes: usize) {
+    let (mut vidro, history) = match board_from_moves(moves) {
+        Ok(position) => position,
+        Err(e) => {
+            println!("{}", e);
+            return;
+        }
+    };
+    println!("{}", vidro.to_string());
 
-            let mut best_move: MoveBit;
-            if move_count < RANDOM_MOVES_UNTIL {
-                println!("----ランダムループを選択----");
-                let mut moves = MoveList::new();
-                vidro.generate_legal_moves(&mut moves);
-                if moves.is_empty() {
-                    break;
-                }
-                best_move = (*moves.choose(&mut rand::rng()).unwrap()).clone();
-            } else {
-                let is_turn_humen = vidro.turn == human_turn * 2 - 1;
-                if is_turn_humen {
-                    println!("手を選択");
-                    let legal_moves = vidro
-                        .iter_legal_move()
-                        .into_iter()
-                        .filter(move |&mv| !vidro.check_illegal_move(mv, prev_hash))
-                        .collect();
-                    MoveBit::print_vec_to_string(&legal_moves);
-                    while {
-                        best_move = Bitboard::read_to_move();
-                        !legal_moves.contains(&best_move)
-                    } {}
-                } else {
-                    println!("思考中...");
-
-                    let tt_for_thread = Arc::clone(&tt);
-
-                    tt.lock().unwrap().clear();
-
-                    let score;
-                    (score, best_move) = {
-                        let result =
-                            find_best_move(&mut vidro, depth, tt_for_thread, prev_hash, &evaluate);
-                        if result.1.is_some() {
-                            (result.0, result.1.unwrap())
-                        } else {
-                            println!("指せる手がありません。手番プレイヤーの負けです");
-                            break;
-                        }
-                    };
-                    println!(
-                        "\nmtd-f 決定手: {} 評価値{} 勝率: {}",
-                        best_move.to_string(),
-                        score,
-                        sigmoid(score as f32 / EVAL_VALUE_MALTIPLIER)
-                    );
-                }
+    let options = ThreatSearchOptions {
+        max_depth,
+        max_nodes,
+    };
+    let start = Instant::now();
+    let (result, nodes) = threat_space_search(&mut vidro, &history, &options);
+    match result {
+        ThreatSearchResult::Win(line) => println!(
+            "{}手で勝ち: {}",
+            line.len(),
+            line.iter()
+                .map(|m| m.to_string())
+                .collect::<Vec<_>>()
+                .join(" ")
+        ),
+        ThreatSearchResult::NotFound => {
+            println!("{}手以内の勝ちは見つかりませんでした", max_depth)
+        }
+        ThreatSearchResult::Aborted => println!("ノード数の上限に達しました"),
+    }
+    let brinkmate_moves = generate_brinkmate_moves(&mut vidro, history.last().copied(), &history);
+    if !brinkmate_moves.is_empty() {
+        println!(
+            "必至をかける手: {}",
+            brinkmate_moves
+                .iter()
+                .map(|m| m.to_string())
+                .collect::<Vec<_>>()
+                .join(" ")
+        );
+    }
+    println!(
+        "ノード数: {} 時間: {:.2}s",
+        nodes,
+        start.elapsed().as_secs_f32()
+    );
+}
+
+fn trace_mode(
+    options: &SearchOptions,
+    moves: &str,
+    hash_mb: usize,
+    trace: SearchTrace,
+    format: TraceFormat,
+    out: &str,
+) {
+    let (mut vidro, history) = match board_from_moves(moves) {
+        Ok(position) => position,
+        Err(e) => {
+            println!("{}", e);
+            return;
+        }
+    };
+    let Some(ai_ctx) = load_play_model() else {
+        return;
+    };
+    let evaluate = ai_ctx.evaluator(&options.params);
+
+    let trace = Arc::new(Mutex::new(trace));
+    let options = SearchOptions {
+        deterministic: true,
+        trace: Some(trace.clone()),
+        ..options.clone()
+    };
+    let tt = TranspositionTable::new(hash_mb);
+    let result = find_best_move(
+        &mut vidro,
+        &options,
+        &tt,
+        &history,
+        &evaluate,
+        &SilentObserver,
+    );
+
+    let trace = trace.lock().unwrap();
+    let text = match format {
+        TraceFormat::Json => trace.to_json().to_string(),
+        TraceFormat::Dot => trace.to_dot(),
+    };
+    if let Err(e) = std::fs::write(out, text) {
+        println!("書き込めません: {}: {}", out, e);
+        return;
+    }
+    println!(
+        "最善手: {} 評価値: {} 深さ: {} 記録したノード数: {}{} -> {}",
+        result.best_move.map_or("-".to_string(), |m| m.to_string()),
+        result.score,
+        trace.depth,
+        trace.nodes.len(),
+        if trace.truncated {
+            " (上限で打ち切り)"
+        } else {
+            ""
+        },
+        out
+    );
+}
+
+fn book_mode(
+    options: &SearchOptions,
+    settings: &BookBuildSettings,
+    path: &str,
+    games: usize,
+    hash_mb: usize,
+) {
+    let mut book = if std::path::Path::new(path).exists() {
+        match OpeningBook::load(path) {
+            Ok(book) => book,
+            Err(e) => {
+                println!("定跡ファイルを読み込めません: {}: {}", path, e);
+                return;
             }
-            println!("\n決定手: {}", best_move.to_string());
-            prev_hash = Some(vidro.to_compression_bod());
-            match vidro.apply_force_with_check_illegal_move(best_move, prev_hash) {
-                Ok(()) => {}
-                Err(()) => {
-                    println!("反則手。手番プレイヤーの負けです");
-                    break;
-                }
-            };
+        }
+    } else {
+        OpeningBook::default()
+    };
+    let Some(ai_ctx) = load_play_model() else {
+        return;
+    };
+    let evaluate = ai_ctx.evaluator(&options.params);
+    let tt = TranspositionTable::new(hash_mb);
+
+    let start = Instant::now();
+    let searched = build_book_from_search(&mut book, options, &tt, &evaluate, settings);
+    println!(
+        "探索した局面: {} 時間: {:.2}s",
+        searched,
+        start.elapsed().as_secs_f32()
+    );
+
+    //登録した手を散らして指し、勝った側の手の重みを増やす
+    let options = SearchOptions {
+        book: Some(BookOptions {
+            book: Arc::new(book.clone()),
+            depth: settings.max_ply,
+            randomness: 1.0,
+        }),
+        ..options.clone()
+    };
+    let mut first = SearchPlayer::new(options.clone(), &tt, &evaluate, &SilentObserver);
+    let mut second = SearchPlayer::new(options, &tt, &evaluate, &SilentObserver);
+    for i in 0..games {
+        let mut game = Game::new();
+        let outcome = play_game(&mut game, [&mut first, &mut second], MAX_MOVES, false);
+        book.add_game(&game.moves, outcome.score_for_first(), settings.max_ply);
+        println!("対局 {}/{}: {}", i + 1, games, outcome);
+    }
+
+    if let Err(e) = book.save(path) {
+        println!("定跡ファイルを保存できません: {}: {}", path, e);
+        return;
+    }
+    println!(
+        "局面数: {} 手の数: {} -> {}",
+        book.positions.len(),
+        book.num_moves(),
+        path
+    );
+}
+
+fn bench_mode(options: &SearchOptions, hash_mb: usize) {
+    let Some(ai_ctx) = load_play_model() else {
+        return;
+    };
+    let evaluate = ai_ctx.evaluator(&options.params);
+
+    let start = Instant::now();
+    let bench = run_bench(options, hash_mb, &evaluate);
+    for (moves, result) in BENCH_POSITIONS.iter().zip(&bench.results) {
+        println!(
+            "[{}] 最善手: {} 評価値: {} 深さ: {} ノード数: {}",
+            moves,
+            result.best_move.map_or("-".to_string(), |m| m.to_string()),
+            result.score,
+            result.depth,
+            result.nodes
+        );
+    }
+    println!(
+        "シグネチャ: {:016x} 総ノード数: {} 時間: {:.2}s",
+        bench.signature,
+        bench.nodes,
+        start.elapsed().as_secs_f32()
+    );
+}
+
+//置換表ファイルを読み込む。ファイルがなければ何もしない
+fn load_cache(tt: &TranspositionTable, cache: &CacheSettings, version: u64) {
+    match tt.load(&cache.path, version) {
+        Ok(n) => println!("置換表を読み込みました: {} ({}件)", cache.path, n),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => println!("置換表を読み込めません: {}: {}", cache.path, e),
+    }
+}
 
-            move_count += 1;
+fn save_cache(tt: &TranspositionTable, cache: &CacheSettings, version: u64) {
+    match tt.save(&cache.path, version, cache.min_depth) {
+        Ok(n) => println!("置換表を保存しました: {} ({}件)", cache.path, n),
+        Err(e) => println!("置換表を保存できません: {}: {}", cache.path, e),
+    }
+}
+
+fn analyze_mode(
+    options: &SearchOptions,
+    moves: &str,
+    hash_mb: usize,
+    cache: Option<CacheSettings>,
+    output: OutputFormat,
+) {
+    let (mut vidro, history) = match board_from_moves(moves) {
+        Ok(position) => position,
+        Err(e) => {
+            println!("{}", e);
+            return;
+        }
+    };
+    let observer: Box<dyn SearchObserver> = match output {
+        OutputFormat::Console => Box::new(ConsoleObserver::new(options.params.eval_multiplier)),
+        OutputFormat::Json => Box::new(JsonObserver),
+        OutputFormat::Silent => Box::new(SilentObserver),
+    };
+    let is_json = matches!(output, OutputFormat::Json);
+    if !is_json {
+        println!("{}", vidro.to_string());
+    }
+
+    let Some(ai_ctx) = load_play_model() else {
+        return;
+    };
+    let evaluate = ai_ctx.evaluator(&options.params);
+    let tt = TranspositionTable::new(hash_mb);
+    let version = cache_version(&ai_ctx, options);
+    if let Some(cache) = &cache {
+        load_cache(&tt, cache, version);
+    }
+
+    let result = find_best_move(&mut vidro, options, &tt, &history, &evaluate, &*observer);
+    if let Some(cache) = &cache {
+        save_cache(&tt, cache, version);
+    }
+    //JSONでは結果もobserverが出力済み
+    if is_json {
+        return;
+    }
+    println!("\n");
+    for (i, line) in result.lines.iter().enumerate() {
+        println!(
+            "{}. 評価値: {:6} 勝率: {:.3} 深さ: {:2} 読み筋: {}",
+            i + 1,
+            line.score,
+            sigmoid(line.score as f32 / options.params.eval_multiplier),
+            line.depth,
+            line.pv
+                .iter()
+                .map(|m| m.to_string())
+                .collect::<Vec<_>>()
+                .join(" ")
+        );
+    }
+}
+
+//mcts_optionsを指定した場合はアルファベータ探索の代わりにMCTSで指す
+fn play_mode(
+    options: &SearchOptions,
+    mcts_options: Option<MctsOptions>,
+    human_turn: i8,
+    hash_mb: usize,
+    cache: Option<CacheSettings>,
+    ponder: bool,
+) {
+    let Some(ai_ctx) = load_play_model() else {
+        return;
+    };
+    let evaluate = ai_ctx.evaluator(&options.params);
+    let observer = ConsoleObserver::new(options.params.eval_multiplier);
+
+    let tt = TranspositionTable::new(hash_mb);
+    let version = cache_version(&ai_ctx, options);
+    if let Some(cache) = &cache {
+        load_cache(&tt, cache, version);
+    }
+
+    let mut ai: Box<dyn Player> = match mcts_options {
+        Some(mcts_options) => {
+            let mut player = MctsPlayer::new(Mcts::new(&ai_ctx, mcts_options));
+            player.verbose = true;
+            Box::new(player)
+        }
+        None => {
+            let mut player = SearchPlayer::new(options.clone(), &tt, &evaluate, &observer);
+            player.verbose = true;
+            player.ponder = ponder;
+            player.max_moves = Some(MAX_MOVES);
+            Box::new(player)
         }
+    };
+    let mut human = HumanPlayer;
+
+    loop {
+        let mut game = Game::new();
+        let players: [&mut dyn Player; 2] = if human_turn == 1 {
+            [&mut human, ai.as_mut()]
+        } else {
+            [ai.as_mut(), &mut human]
+        };
+        play_game(&mut game, players, MAX_MOVES, true);
         println!("\n対局終了");
+        if let Some(cache) = &cache {
+            save_cache(&tt, cache, version);
+        }
     }
 }
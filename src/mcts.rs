@@ -0,0 +1,395 @@
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicI64, AtomicU32, AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::bitboard::{Bitboard, MoveBit, MoveList};
+use crate::eval::{AiModel, sigmoid};
+use crate::snapshot_features::BoardSnapshotFeatures;
+
+#[derive(Clone, Debug)]
+pub struct MctsOptions {
+    pub playouts: usize, //1回の探索で行うプレイアウト数(再利用した木の訪問回数は含めない)
+    pub threads: usize,
+    pub time_limit: Option<Duration>,
+    pub c_puct: f32,
+    pub prior_temperature: f32, //子局面の評価から事前確率を作るときのsoftmaxの温度
+    pub virtual_loss: u32,      //探索中の枝を他のスレッドが選びにくくするための仮の負け数
+}
+
+impl Default for MctsOptions {
+    fn default() -> Self {
+        Self {
+            playouts: 10000,
+            threads: 1,
+            time_limit: None,
+            c_puct: 1.5,
+            prior_temperature: 1.0,
+            virtual_loss: 3,
+        }
+    }
+}
+
+//1手で勝てる子局面の事前確率用のロジット
+const WIN_PRIOR_LOGIT: f32 = 10.0;
+//価値の合計をアトミックな整数で持つための倍率
+const VALUE_SCALE: f64 = 1_000_000.0;
+const DRAW_VALUE: f32 = 0.0;
+
+struct Node {
+    mv: Option<MoveBit>, //親からこの局面に至る手(ルートはNone)
+    hash: u64,
+    prior: f32,
+    visits: AtomicU32,
+    value_sum: AtomicI64, //親の手番側から見た価値(-1..1)の合計(VALUE_SCALE倍)
+    virtual_loss: AtomicU32,
+    children: OnceLock<Vec<Node>>, //展開済みならSome
+}
+
+impl Node {
+    fn new(mv: Option<MoveBit>, hash: u64, prior: f32) -> Self {
+        Self {
+            mv,
+            hash,
+            prior,
+            visits: AtomicU32::new(0),
+            value_sum: AtomicI64::new(0),
+            virtual_loss: AtomicU32::new(0),
+            children: OnceLock::new(),
+        }
+    }
+
+    //親の手番側から見た平均価値
+    fn value(&self) -> f32 {
+        let visits = self.visits.load(Ordering::Relaxed);
+        if visits == 0 {
+            return 0.0;
+        }
+        (self.value_sum.load(Ordering::Relaxed) as f64 / VALUE_SCALE / visits as f64) as f32
+    }
+
+    //訪問回数の多い順(同じなら事前確率の高い順)に並べた子
+    fn sorted_children(&self) -> Vec<&Node> {
+        let mut children: Vec<&Node> = self
+            .children
+            .get()
+            .map_or(Vec::new(), |c| c.iter().collect());
+        children.sort_by(|a, b| {
+            b.visits
+                .load(Ordering::Relaxed)
+                .cmp(&a.visits.load(Ordering::Relaxed))
+                .then(b.prior.total_cmp(&a.prior))
+        });
+        children
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct MctsChild {
+    pub mv: MoveBit,
+    pub visits: u32,
+    pub value: f32, //この手を指した側から見た平均価値(-1..1)
+    pub prior: f32,
+}
+
+#[derive(Clone, Debug)]
+pub struct MctsResult {
+    pub best_move: Option<MoveBit>,
+    pub value: f32, //手番側から見た最善手の平均価値(-1..1)
+    pub pv: Vec<MoveBit>,
+    pub children: Vec<MctsChild>, //訪問回数の多い順。学習の目標分布にも使える
+    pub playouts: usize,
+    pub reused_visits: u32, //前回の探索木から引き継いだルートの訪問回数
+    pub elapsed: Duration,
+}
+
+//AiModelを評価関数・方策として使うモンテカルロ木探索。探索木は次の手番で再利用する
+pub struct Mcts<'a> {
+    model: &'a AiModel,
+    options: MctsOptions,
+    root: Option<Node>,
+    root_prev: Option<u64>, //rootの1つ前の局面
+}
+
+impl<'a> Mcts<'a> {
+    pub fn new(model: &'a AiModel, options: MctsOptions) -> Self {
+        Self {
+            model,
+            options,
+            root: None,
+            root_prev: None,
+        }
+    }
+
+    //前回までの探索木を捨てる
+    pub fn clear(&mut self) {
+        self.root = None;
+        self.root_prev = None;
+    }
+
+    //history: これまでに現れた局面(古い順、最後が直前の局面)
+    pub fn search(&mut self, board: &Bitboard, history: &[u64]) -> MctsResult {
+        let start = Instant::now();
+        let deadline = self.options.time_limit.map(|limit| start + limit);
+        let hash = board.to_compression_bod();
+        let root = self
+            .reuse_subtree(hash, history)
+            .unwrap_or_else(|| Node::new(None, hash, 1.0));
+        let reused_visits = root.visits.load(Ordering::Relaxed);
+
+        let started_playouts = AtomicUsize::new(0);
+        let playouts = self.options.playouts.max(1);
+        thread::scope(|s| {
+            for _ in 0..self.options.threads.max(1) {
+                s.spawn(|| {
+                    while started_playouts.fetch_add(1, Ordering::Relaxed) < playouts
+                        && deadline.is_none_or(|d| Instant::now() < d)
+                    {
+                        self.playout(&root, board, history);
+                    }
+                });
+            }
+        });
+
+        let children: Vec<MctsChild> = root
+            .sorted_children()
+            .into_iter()
+            .map(|child| MctsChild {
+                mv: child.mv.unwrap(),
+                visits: child.visits.load(Ordering::Relaxed),
+                value: child.value(),
+                prior: child.prior,
+            })
+            .collect();
+        let mut pv = Vec::new();
+        let mut node = &root;
+        while let Some(&child) = node.sorted_children().first() {
+            if child.visits.load(Ordering::Relaxed) == 0 {
+                break;
+            }
+            pv.push(child.mv.unwrap());
+            node = child;
+        }
+
+        let result = MctsResult {
+            best_move: children.first().map(|c| c.mv),
+            value: children.first().map_or(0.0, |c| c.value),
+            pv,
+            children,
+            playouts: started_playouts.load(Ordering::Relaxed).min(playouts),
+            reused_visits,
+            elapsed: start.elapsed(),
+        };
+        self.root = Some(root);
+        self.root_prev = history.last().copied();
+        result
+    }
+
+    //前回のルートから今の局面まで実際に指された手順をたどり、その部分木を取り出す
+    fn reuse_subtree(&mut self, hash: u64, history: &[u64]) -> Option<Node> {
+        let mut node = self.root.take()?;
+        let path: Vec<u64> = if node.hash == hash {
+            Vec::new()
+        } else {
+            let pos = history.iter().rposition(|&h| h == node.hash)?;
+            history[pos + 1..].iter().copied().chain([hash]).collect()
+        };
+        //ルートの直前の局面が違うと指せない手(元の局面に戻る手)が変わるので使わない
+        let prev_of_root = (history.len() - path.len())
+            .checked_sub(1)
+            .map(|i| history[i]);
+        if prev_of_root != self.root_prev {
+            return None;
+        }
+        for h in path {
+            node = node
+                .children
+                .into_inner()?
+                .into_iter()
+                .find(|child| child.hash == h)?;
+        }
+        node.mv = None;
+        Some(node)
+    }
+
+    fn playout(&self, root: &Node, board: &Bitboard, history: &[u64]) {
+        let mut board = *board;
+        let mut prev_hash = history.last().copied();
+        let mut path: Vec<&Node> = vec![root];
+
+        //手番側から見た末端の価値
+        let value = loop {
+            let node = *path.last().unwrap();
+            if board.game_over() {
+                break (board.win_turn() * board.turn as i16) as f32;
+            }
+            //探索経路上か対局中に既に現れた局面なら千日手(ルート局面自体は判定しない)
+            if path.len() > 1
+                && (path[..path.len() - 1].iter().any(|n| n.hash == node.hash)
+                    || history.contains(&node.hash))
+            {
+                break DRAW_VALUE;
+            }
+            let Some(children) = node.children.get() else {
+                node.children
+                    .get_or_init(|| self.expand(&board, node.hash, prev_hash));
+                let z = self
+                    .model
+                    .eval_score(board.to_snapshot(prev_hash).iter_feature_indices());
+                break 2.0 * sigmoid(z) - 1.0;
+            };
+            //指せる手がなければ手番側の負け
+            if children.is_empty() {
+                break -1.0;
+            }
+
+            let child = self.select_child(node, children);
+            child
+                .virtual_loss
+                .fetch_add(self.options.virtual_loss, Ordering::Relaxed);
+            board
+                .apply_force_with_check_illegal_move(child.mv.unwrap(), prev_hash)
+                .expect("expanded moves must be legal");
+            prev_hash = Some(node.hash);
+            path.push(child);
+        };
+
+        //末端から手番を入れ替えながら価値を戻す
+        let mut value = value;
+        for (i, node) in path.iter().enumerate().rev() {
+            value = -value;
+            node.visits.fetch_add(1, Ordering::Relaxed);
+            node.value_sum
+                .fetch_add((value as f64 * VALUE_SCALE) as i64, Ordering::Relaxed);
+            if i > 0 {
+                node.virtual_loss
+                    .fetch_sub(self.options.virtual_loss, Ordering::Relaxed);
+            }
+        }
+    }
+
+    //PUCT: Q + c_puct * P * sqrt(N) / (1 + n)。仮の負けは負けとして数える
+    fn select_child<'n>(&self, node: &Node, children: &'n [Node]) -> &'n Node {
+        let parent_visits = node.visits.load(Ordering::Relaxed) as f32 + 1.0;
+        let exploration = self.options.c_puct * parent_visits.sqrt();
+        let puct = |child: &Node| {
+            let visits = child.visits.load(Ordering::Relaxed);
+            let virtual_loss = child.virtual_loss.load(Ordering::Relaxed);
+            let total = visits + virtual_loss;
+            let q = if total == 0 {
+                0.0
+            } else {
+                ((child.value_sum.load(Ordering::Relaxed) as f64 / VALUE_SCALE
+                    - virtual_loss as f64)
+                    / total as f64) as f32
+            };
+            q + exploration * child.prior / (1 + total) as f32
+        };
+        children
+            .iter()
+            .max_by(|a, b| puct(a).total_cmp(&puct(b)))
+            .unwrap()
+    }
+
+    //合法手ごとに子を作り、子局面の評価のsoftmaxを事前確率にする
+    fn expand(&self, board: &Bitboard, hash: u64, prev_hash: Option<u64>) -> Vec<Node> {
+        let mut moves = MoveList::new();
+        board.generate_legal_moves(&mut moves);
+
+        let mut children = Vec::with_capacity(moves.len());
+        let mut logits = Vec::with_capacity(moves.len());
+        for &mv in moves.iter() {
+            let mut next = *board;
+            if next
+                .apply_force_with_check_illegal_move(mv, prev_hash)
+                .is_err()
+            {
+                continue;
+            }
+            let logit = if next.game_over() {
+                (next.win_turn() * board.turn as i16) as f32 * WIN_PRIOR_LOGIT
+            } else {
+                -self
+                    .model
+                    .eval_score(next.to_snapshot(Some(hash)).iter_feature_indices())
+            };
+            children.push(Node::new(Some(mv), next.to_compression_bod(), 0.0));
+            logits.push(logit / self.options.prior_temperature);
+        }
+
+        let max_logit = logits.iter().fold(f32::NEG_INFINITY, |a, &b| a.max(b));
+        let weights: Vec<f32> = logits.iter().map(|&l| (l - max_logit).exp()).collect();
+        let total: f32 = weights.iter().sum();
+        for (child, w) in children.iter_mut().zip(weights) {
+            child.prior = w / total;
+        }
+        children
+    }
+}
+
+#[test]
+fn test_mcts_finds_winning_flick() {
+    use crate::checkmate_search::checkmate_in_one_move;
+    use crate::random_state_generator::random_state_generator_with_rng;
+    use rand::{SeedableRng, rngs::StdRng};
+
+    let mut rng = StdRng::seed_from_u64(0);
+    let model = AiModel::rand_new_with_rng(&mut rng);
+    let mut found = 0;
+    for _ in 0..2000 {
+        let (mut board, prev_hash) = random_state_generator_with_rng(12, &mut rng);
+        if board.game_over() || !checkmate_in_one_move(&mut board, prev_hash) {
+            continue;
+        }
+        let history: Vec<u64> = prev_hash.into_iter().collect();
+        let mut mcts = Mcts::new(
+            &model,
+            MctsOptions {
+                playouts: 300,
+                ..Default::default()
+            },
+        );
+        let result = mcts.search(&board, &history);
+        let mv = result.best_move.unwrap();
+        board
+            .apply_force_with_check_illegal_move(mv, prev_hash)
+            .unwrap();
+        assert!(board.game_over());
+        assert_eq!(board.win_turn() * board.turn as i16, -1);
+
+        found += 1;
+        if found >= 5 {
+            break;
+        }
+    }
+    assert!(found > 0);
+}
+
+#[test]
+fn test_mcts_reuses_tree() {
+    use rand::{SeedableRng, rngs::StdRng};
+
+    let model = AiModel::rand_new_with_rng(&mut StdRng::seed_from_u64(0));
+    let mut mcts = Mcts::new(
+        &model,
+        MctsOptions {
+            playouts: 500,
+            ..Default::default()
+        },
+    );
+    let mut board = Bitboard::new_initial();
+    let mut history: Vec<u64> = Vec::new();
+    let first = mcts.search(&board, &history);
+
+    //読み筋どおりに2手進めると、その局面の部分木を引き継ぐ
+    for &mv in &first.pv[..2] {
+        let hash = board.to_compression_bod();
+        board
+            .apply_force_with_check_illegal_move(mv, history.last().copied())
+            .unwrap();
+        history.push(hash);
+    }
+    let second = mcts.search(&board, &history);
+    assert!(second.reused_visits > 0);
+    assert_eq!(second.playouts, 500);
+}
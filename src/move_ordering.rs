@@ -0,0 +1,102 @@
+use crate::bitboard::{BITBOD_WIDTH, FIELD_BOD_HEIGHT, MoveBit};
+
+pub const MAX_PLY: usize = 128;
+
+//手をidx(マス)とangle_idx(0~7: flick, 8: set)で表の添字にする
+const MOVE_TABLE_SIZE: usize = (BITBOD_WIDTH * FIELD_BOD_HEIGHT) as usize * 9;
+const HISTORY_MAX: i32 = 1 << 14;
+
+const TT_MOVE_SCORE: i32 = 1 << 30;
+//キラー手1, キラー手2, カウンター手
+const KILLER_SCORES: [i32; 3] = [1 << 29, 1 << 28, 1 << 27];
+
+fn move_table_idx(mv: MoveBit) -> usize {
+    mv.idx as usize * 9 + mv.angle_idx as usize
+}
+
+//評価関数を呼ばずに手を並べ替えるための統計。探索スレッドごとに持つ
+pub struct MoveOrdering {
+    killers: [[Option<MoveBit>; 2]; MAX_PLY], //各plyでbetaカットを起こした手
+    history: [[i32; MOVE_TABLE_SIZE]; 2],     //[手番][手] betaカットを起こした頻度
+    counter_moves: [[Option<MoveBit>; MOVE_TABLE_SIZE]; 2], //[手番][直前の相手の手] に対して有効だった手
+    played_moves: [Option<MoveBit>; MAX_PLY],               //探索中の各plyで指している手
+}
+
+impl MoveOrdering {
+    pub fn new() -> Self {
+        Self {
+            killers: [[None; 2]; MAX_PLY],
+            history: [[0; MOVE_TABLE_SIZE]; 2],
+            counter_moves: [[None; MOVE_TABLE_SIZE]; 2],
+            played_moves: [None; MAX_PLY],
+        }
+    }
+
+    pub fn set_played_move(&mut self, ply: usize, mv: Option<MoveBit>) {
+        if ply < MAX_PLY {
+            self.played_moves[ply] = mv;
+        }
+    }
+
+    //plyの局面に至る直前の手(ルートでは不明なのでNone)
+    pub fn previous_move(&self, ply: usize) -> Option<MoveBit> {
+        if ply == 0 || ply > MAX_PLY {
+            None
+        } else {
+            self.played_moves[ply - 1]
+        }
+    }
+
+    //大きいほど先に探索する
+    pub fn score(&self, mv: MoveBit, turn_idx: usize, ply: usize, tt_move: Option<MoveBit>) -> i32 {
+        if Some(mv) == tt_move {
+            return TT_MOVE_SCORE;
+        }
+        match self.killer_rank(mv, turn_idx, ply) {
+            Some(rank) => KILLER_SCORES[rank],
+            None => self.history[turn_idx][move_table_idx(mv)],
+        }
+    }
+
+    //キラー手(0, 1)かカウンター手(2)なら順位を返す
+    pub fn killer_rank(&self, mv: MoveBit, turn_idx: usize, ply: usize) -> Option<usize> {
+        if ply < MAX_PLY
+            && let Some(rank) = self.killers[ply].iter().position(|k| *k == Some(mv))
+        {
+            return Some(rank);
+        }
+        self.previous_move(ply)
+            .filter(|&prev| self.counter_moves[turn_idx][move_table_idx(prev)] == Some(mv))
+            .map(|_| 2)
+    }
+
+    //betaカットを起こした手を記録し、それより前に試して失敗した手の評価を下げる
+    pub fn update_cutoff(
+        &mut self,
+        mv: MoveBit,
+        turn_idx: usize,
+        ply: usize,
+        depth: usize,
+        tried_moves: &[MoveBit],
+    ) {
+        if ply < MAX_PLY && self.killers[ply][0] != Some(mv) {
+            self.killers[ply][1] = self.killers[ply][0];
+            self.killers[ply][0] = Some(mv);
+        }
+        if let Some(prev) = self.previous_move(ply) {
+            self.counter_moves[turn_idx][move_table_idx(prev)] = Some(mv);
+        }
+
+        let bonus = (depth * depth).min(400) as i32;
+        self.update_history(mv, turn_idx, bonus);
+        for &tried in tried_moves {
+            self.update_history(tried, turn_idx, -bonus);
+        }
+    }
+
+    //値がHISTORY_MAXを超えないように、大きい値ほど変化を小さくする
+    fn update_history(&mut self, mv: MoveBit, turn_idx: usize, bonus: i32) {
+        let entry = &mut self.history[turn_idx][move_table_idx(mv)];
+        *entry += bonus - *entry * bonus.abs() / HISTORY_MAX;
+    }
+}
@@ -0,0 +1,278 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::sync::Arc;
+
+use rand::Rng;
+use rand::distr::{Distribution, weighted::WeightedIndex};
+use serde::{Deserialize, Serialize};
+
+use crate::bitboard::{Bitboard, MoveBit};
+use crate::game::Game;
+use crate::search::{SearchOptions, find_best_move};
+use crate::search_observer::SilentObserver;
+use crate::snapshot::BoardSnapshot;
+use crate::symmetry::{Symmetry, canonical_key};
+use crate::transposition_table::TranspositionTable;
+
+//定跡の1手
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct BookMove {
+    pub mv: MoveBit,        //ファイル中では正規化した向きの局面での手
+    pub weight: u32,        //選ぶ重み(0なら選ばない)
+    pub score: Option<i16>, //探索の評価値(手番側から見た値)
+    pub depth: usize,       //scoreを得た探索の深さ
+    pub games: u32,         //この手を指した対局数
+    pub points: f32,        //その対局での手番側の勝ち点の合計(勝ち1, 引き分け0.5)
+}
+
+//局面 -> 定跡手。対称な局面は同じキーにまとめる
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct OpeningBook {
+    pub positions: HashMap<u64, Vec<BookMove>>, //canonical_key -> 手
+}
+
+//探索で定跡を使う設定
+#[derive(Clone, Debug)]
+pub struct BookOptions {
+    pub book: Arc<OpeningBook>,
+    pub depth: usize,    //対局の何手目まで定跡を使うか
+    pub randomness: f32, //0なら最も重みの大きい手、1なら重みに比例した確率で選ぶ
+}
+
+//探索で定跡を作る設定
+pub struct BookBuildSettings {
+    pub max_ply: usize, //初期局面から何手目までの局面を登録するか
+    pub width: usize,   //1つの局面で登録する手の数
+    pub margin: i16,    //最善手との評価値の差がこれ以内の手だけ登録する
+}
+
+impl OpeningBook {
+    pub fn load(file_path: &str) -> std::io::Result<Self> {
+        let file = File::open(file_path)?;
+        serde_json::from_reader(BufReader::new(file)).map_err(std::io::Error::other)
+    }
+
+    pub fn save(&self, file_path: &str) -> std::io::Result<()> {
+        let file = File::create(file_path)?;
+        serde_json::to_writer(BufWriter::new(file), self).map_err(std::io::Error::other)
+    }
+
+    pub fn num_moves(&self) -> usize {
+        self.positions.values().map(|moves| moves.len()).sum()
+    }
+
+    fn entry_mut(&mut self, board: &Bitboard, mv: MoveBit) -> &mut BookMove {
+        let (key, sym) = canonical_key(board);
+        let mv = sym.apply_move(mv);
+        let moves = self.positions.entry(key).or_default();
+        let idx = match moves.iter().position(|m| m.mv == mv) {
+            Some(idx) => idx,
+            None => {
+                moves.push(BookMove {
+                    mv,
+                    weight: 0,
+                    score: None,
+                    depth: 0,
+                    games: 0,
+                    points: 0.0,
+                });
+                moves.len() - 1
+            }
+        };
+        &mut moves[idx]
+    }
+
+    //探索の結果を加える。評価値は深い探索のもので上書きする
+    pub fn add_search_move(
+        &mut self,
+        board: &Bitboard,
+        mv: MoveBit,
+        score: i16,
+        depth: usize,
+        weight: u32,
+    ) {
+        let entry = self.entry_mut(board, mv);
+        if entry.score.is_none() || depth >= entry.depth {
+            entry.score = Some(score);
+            entry.depth = depth;
+        }
+        entry.weight = entry.weight.max(weight);
+    }
+
+    //初期局面からの対局の結果を最初のmax_ply手に加える。勝った側の手は重みを2、引き分けなら1増やす
+    pub fn add_game(&mut self, moves: &[MoveBit], score_for_first: f32, max_ply: usize) {
+        let mut board = Bitboard::new_initial();
+        let mut prev_hash = None;
+        for &mv in moves.iter().take(max_ply) {
+            let points = if board.turn == 1 {
+                score_for_first
+            } else {
+                1.0 - score_for_first
+            };
+            let entry = self.entry_mut(&board, mv);
+            entry.games += 1;
+            entry.points += points;
+            entry.weight += (points * 2.0) as u32;
+
+            let hash = board.to_compression_bod();
+            if board
+                .apply_force_with_check_illegal_move(mv, prev_hash)
+                .is_err()
+            {
+                break;
+            }
+            prev_hash = Some(hash);
+        }
+    }
+
+    //局面の定跡手を実際の盤の向きに戻して返す(直前の局面に戻る手は除く)
+    pub fn moves(&self, board: &Bitboard, prev_hash: Option<u64>) -> Vec<BookMove> {
+        let (key, sym) = canonical_key(board);
+        let Some(moves) = self.positions.get(&key) else {
+            return Vec::new();
+        };
+        let inverse = sym.inverse();
+        let mut board = *board;
+        let legal: Vec<MoveBit> = board.iter_legal_move().collect();
+        moves
+            .iter()
+            .map(|m| BookMove {
+                mv: inverse.apply_move(m.mv),
+                ..m.clone()
+            })
+            .filter(|m| legal.contains(&m.mv) && !board.check_illegal_move(m.mv, prev_hash))
+            .collect()
+    }
+
+    //重みに従って定跡手を選ぶ。randomnessが0なら最も重みが大きい手(同じなら評価値が高い手)
+    pub fn choose(
+        &self,
+        board: &Bitboard,
+        prev_hash: Option<u64>,
+        randomness: f32,
+        rng: &mut impl Rng,
+    ) -> Option<BookMove> {
+        let moves: Vec<BookMove> = self
+            .moves(board, prev_hash)
+            .into_iter()
+            .filter(|m| m.weight > 0)
+            .collect();
+        if randomness <= 0.0 {
+            return moves.into_iter().rev().max_by_key(|m| (m.weight, m.score));
+        }
+        let weights: Vec<f64> = moves
+            .iter()
+            .map(|m| (m.weight as f64).powf(1.0 / randomness as f64))
+            .collect();
+        let dist = WeightedIndex::new(&weights).ok()?;
+        Some(moves[dist.sample(rng)].clone())
+    }
+}
+
+//初期局面から探索で序盤を展開して定跡に加える。探索した局面の数を返す
+//各局面でMultiPVの上位width手のうち最善手との差がmargin以内の手を登録し、その先の局面も同じように読む
+pub fn build_book_from_search<F>(
+    book: &mut OpeningBook,
+    options: &SearchOptions,
+    tt: &TranspositionTable,
+    evaluate: &F,
+    settings: &BookBuildSettings,
+) -> usize
+where
+    F: Fn(&BoardSnapshot) -> i16 + Sync,
+{
+    let mut searched = 0;
+    let mut visited = HashSet::new();
+    let mut queue = vec![Game::new()];
+    while let Some(game) = queue.pop() {
+        if game.moves.len() >= settings.max_ply
+            || game.outcome().is_some()
+            || !visited.insert(canonical_key(&game.board).0)
+        {
+            continue;
+        }
+        //対称な局面では同じ手が向きを変えて並ぶので、その分だけ多く読む
+        let symmetric = Symmetry::all()
+            .filter(|sym| {
+                sym.apply_board(&game.board).to_compression_bod() == game.board.to_compression_bod()
+            })
+            .count();
+        let options = SearchOptions {
+            multi_pv: settings.width.max(1) * symmetric,
+            book: None,
+            ..options.clone()
+        };
+        tt.new_search();
+        let mut board = game.board;
+        let result = find_best_move(
+            &mut board,
+            &options,
+            tt,
+            &game.history,
+            evaluate,
+            &SilentObserver,
+        );
+        searched += 1;
+
+        let mut children = HashSet::new();
+        for line in &result.lines {
+            let Some(&mv) = line.pv.first() else {
+                continue;
+            };
+            let diff = result.score as i32 - line.score as i32;
+            if diff > settings.margin as i32 || children.len() >= settings.width {
+                break;
+            }
+            let mut child = game.clone();
+            if child.apply(mv).is_err() || !children.insert(canonical_key(&child.board).0) {
+                continue;
+            }
+            let weight = (settings.margin as i32 - diff + 1) as u32;
+            book.add_search_move(&game.board, mv, line.score, line.depth, weight);
+            queue.push(child);
+        }
+    }
+    searched
+}
+
+#[test]
+fn test_book_lookup_is_symmetric() {
+    use crate::bitboard_console::board_from_moves;
+
+    let mut book = OpeningBook::default();
+    let (board, _) = board_from_moves("S 0 1").unwrap();
+    let mv = MoveBit::new(4, 4, 8);
+    book.add_search_move(&board, mv, 30, 6, 5);
+
+    //回転・反転した局面でも、同じように変換した手が見つかる
+    for sym in Symmetry::all() {
+        let mapped = sym.apply_board(&board);
+        let moves = book.moves(&mapped, None);
+        assert_eq!(moves.len(), 1);
+        assert_eq!(moves[0].mv, sym.apply_move(mv));
+        assert_eq!(moves[0].score, Some(30));
+    }
+
+    //対局の結果を加えると重みが増え、重みが大きい手が選ばれる
+    let other = MoveBit::new(0, 3, 8);
+    let first = MoveBit::new(0, 1, 8);
+    book.add_search_move(&board, other, 10, 6, 3);
+    for _ in 0..2 {
+        book.add_game(&[first, other], 0.0, 8);
+    }
+    let moves = book.moves(&board, None);
+    let other_entry = moves.iter().find(|m| m.mv == other).unwrap();
+    assert_eq!(other_entry.games, 2);
+    assert_eq!(other_entry.weight, 7);
+    let chosen = book.choose(&board, None, 0.0, &mut rand::rng()).unwrap();
+    assert_eq!(chosen.mv, other);
+
+    //保存して読み込んでも同じ
+    let path = std::env::temp_dir().join(format!("vidro_test_book_{}.json", std::process::id()));
+    let path = path.to_str().unwrap();
+    book.save(path).unwrap();
+    let loaded = OpeningBook::load(path).unwrap();
+    assert_eq!(loaded.num_moves(), book.num_moves());
+    std::fs::remove_file(path).unwrap();
+}
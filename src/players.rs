@@ -0,0 +1,380 @@
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+use std::time::Instant;
+
+use rand::seq::IndexedRandom;
+
+use crate::bitboard::{Bitboard, MoveBit};
+use crate::bitboard_console::BitboardConsole;
+use crate::eval::{AiModel, sigmoid};
+use crate::game::{Game, Player};
+use crate::mcts::Mcts;
+use crate::search::{SearchOptions, SearchResult, find_best_move};
+use crate::search_observer::{SearchObserver, SilentObserver};
+use crate::self_match::select_move_softmax;
+use crate::snapshot::BoardSnapshot;
+use crate::snapshot_features::BoardSnapshotFeatures;
+use crate::transposition_table::TranspositionTable;
+
+fn pv_to_string(pv: &[MoveBit]) -> String {
+    pv.iter()
+        .map(|m| m.to_string())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+//コンソールから手を入力する
+pub struct HumanPlayer;
+
+impl Player for HumanPlayer {
+    fn name(&self) -> String {
+        "human".to_string()
+    }
+    fn choose_move(&mut self, game: &Game) -> MoveBit {
+        println!("手を選択");
+        let legal_moves = game.legal_moves();
+        MoveBit::print_vec_to_string(&legal_moves);
+        loop {
+            let mv = Bitboard::read_to_move();
+            if legal_moves.contains(&mv) {
+                return mv;
+            }
+        }
+    }
+}
+
+//合法手から一様に選ぶ
+pub struct RandomPlayer;
+
+impl Player for RandomPlayer {
+    fn name(&self) -> String {
+        "random".to_string()
+    }
+    fn choose_move(&mut self, game: &Game) -> MoveBit {
+        *game.legal_moves().choose(&mut rand::rng()).unwrap()
+    }
+}
+
+//1手先の局面をモデルで評価して最も良い手を選ぶ(勝てる手があれば指す)
+pub struct GreedyPlayer<'a> {
+    pub model: &'a AiModel,
+}
+
+impl Player for GreedyPlayer<'_> {
+    fn name(&self) -> String {
+        "greedy".to_string()
+    }
+    fn choose_move(&mut self, game: &Game) -> MoveBit {
+        let hash = game.board.to_compression_bod();
+        let turn = game.board.turn as i16;
+        let score = |&mv: &MoveBit| {
+            let mut next = game.board;
+            next.apply_force_with_check_illegal_move(mv, game.prev_hash())
+                .unwrap();
+            //引き分けは0、勝ち負けは有限の最大値にする(0 * INFINITYはNaNになる)
+            if next.game_over() {
+                return (next.win_turn() * turn).signum() as f32 * f32::MAX;
+            }
+            -self
+                .model
+                .eval_score(next.to_snapshot(Some(hash)).iter_feature_indices())
+        };
+        game.legal_moves()
+            .into_iter()
+            .max_by(|a, b| score(a).total_cmp(&score(b)))
+            .unwrap()
+    }
+}
+
+//自己対局のデータ生成と同じく、浅い探索の評価のsoftmaxで確率的に選ぶ
+pub struct SoftmaxPlayer<'a> {
+    pub model: &'a AiModel,
+    pub temperature: f32,
+}
+
+impl Player for SoftmaxPlayer<'_> {
+    fn name(&self) -> String {
+        format!("softmax(T={})", self.temperature)
+    }
+    fn choose_move(&mut self, game: &Game) -> MoveBit {
+        select_move_softmax(
+            &game.board,
+            self.model,
+            self.temperature,
+            game.prev_hash(),
+            &mut rand::rng(),
+        )
+        .unwrap_or_else(|| game.legal_moves()[0])
+    }
+}
+
+//先読みしていた予想手と、その局面の探索結果
+struct Pondered {
+    mv: MoveBit,
+    started: Instant,
+    result: SearchResult,
+}
+
+//アルファベータ探索(find_best_move)で指す
+pub struct SearchPlayer<'a, F> {
+    options: SearchOptions,
+    tt: &'a TranspositionTable,
+    evaluate: &'a F,
+    observer: &'a dyn SearchObserver,
+    pub verbose: bool,            //探索結果を表示する
+    pub ponder: bool,             //相手の手番中に読み筋の予想手を先読みする
+    pub max_moves: Option<usize>, //対局の手数制限。探索で手数制限の引き分けを考慮する
+    ponder_move: Option<MoveBit>,
+    pondered: Option<Pondered>,
+}
+
+impl<'a, F> SearchPlayer<'a, F>
+where
+    F: Fn(&BoardSnapshot) -> i16 + Sync,
+{
+    pub fn new(
+        options: SearchOptions,
+        tt: &'a TranspositionTable,
+        evaluate: &'a F,
+        observer: &'a dyn SearchObserver,
+    ) -> Self {
+        Self {
+            options,
+            tt,
+            evaluate,
+            observer,
+            verbose: false,
+            ponder: false,
+            max_moves: None,
+            ponder_move: None,
+            pondered: None,
+        }
+    }
+
+    fn search(
+        &self,
+        game: &Game,
+        options: &SearchOptions,
+        observer: &dyn SearchObserver,
+    ) -> SearchResult {
+        let mut board = game.board;
+        let options = SearchOptions {
+            moves_left: self
+                .max_moves
+                .map(|max_moves| max_moves.saturating_sub(game.moves.len())),
+            ..options.clone()
+        };
+        find_best_move(
+            &mut board,
+            &options,
+            self.tt,
+            &game.history,
+            self.evaluate,
+            observer,
+        )
+    }
+}
+
+impl<F> Player for SearchPlayer<'_, F>
+where
+    F: Fn(&BoardSnapshot) -> i16 + Sync,
+{
+    fn name(&self) -> String {
+        let mut name = format!("search(depth={}", self.options.depth);
+        if self.options.contempt != 0 {
+            name += &format!(", contempt={}", self.options.contempt);
+        }
+        if let Some(skill) = &self.options.skill {
+            name += &format!(", skill={}", skill.level);
+        }
+        name + ")"
+    }
+    fn new_game(&mut self) {
+        self.ponder_move = None;
+        self.pondered = None;
+    }
+    fn choose_move(&mut self, game: &Game) -> MoveBit {
+        if self.verbose {
+            println!("思考中...");
+        }
+        let pondered = self
+            .pondered
+            .take()
+            .filter(|p| game.last_move() == Some(p.mv) && p.result.best_move.is_some());
+        let result = match pondered {
+            //予想手が的中し、先読みで読み切っているか思考時間を使い切っていればそのまま指す
+            Some(p)
+                if p.result.depth >= self.options.effective_depth()
+                    || self.options.params.is_mate_score(p.result.score)
+                    || self
+                        .options
+                        .time_limit
+                        .is_some_and(|limit| p.started.elapsed() >= limit) =>
+            {
+                if self.verbose {
+                    println!(
+                        "予想手が的中したため先読みの結果を使います 深さ: {}, PV: {}",
+                        p.result.depth,
+                        pv_to_string(&p.result.pv)
+                    );
+                }
+                p.result
+            }
+            //的中したが読み切れていない場合は、置換表を引き継いで残りの時間で探索し直す
+            Some(p) => {
+                let options = SearchOptions {
+                    time_limit: self
+                        .options
+                        .time_limit
+                        .map(|limit| limit.saturating_sub(p.started.elapsed())),
+                    ..self.options.clone()
+                };
+                self.search(game, &options, self.observer)
+            }
+            None => {
+                //前の手までの探索結果は古い世代として残す
+                self.tt.new_search();
+                self.search(game, &self.options, self.observer)
+            }
+        };
+        self.ponder_move = result.pv.get(1).copied();
+
+        let Some(mv) = result.best_move else {
+            return game.legal_moves()[0];
+        };
+        if self.verbose && result.from_book {
+            println!("\n定跡手: {} 評価値{}", mv.to_string(), result.score);
+        } else if self.verbose {
+            println!(
+                "\nmtd-f 決定手: {} 評価値{} 勝率: {}",
+                mv.to_string(),
+                result.score,
+                sigmoid(result.score as f32 / self.options.params.eval_multiplier)
+            );
+            println!("置換表使用率: {}‰", self.tt.hashfull());
+        }
+        mv
+    }
+    //予想手を指した後の局面を、相手が手を決めるまで探索しておく
+    fn ponder(&mut self, game: &Game, stop: &Arc<AtomicBool>) {
+        //再現可能な探索では先読みの結果を使わない
+        let Some(mv) = self
+            .ponder_move
+            .take()
+            .filter(|_| self.ponder && !self.options.deterministic)
+        else {
+            return;
+        };
+        let mut predicted = game.clone();
+        if predicted.apply(mv).is_err() || predicted.board.game_over() {
+            return;
+        }
+        let options = SearchOptions {
+            time_limit: None,
+            stop_signal: Some(stop.clone()),
+            ..self.options.clone()
+        };
+        self.tt.new_search();
+        let started = Instant::now();
+        let result = self.search(&predicted, &options, &SilentObserver);
+        self.pondered = Some(Pondered {
+            mv,
+            started,
+            result,
+        });
+    }
+}
+
+//モンテカルロ木探索で指す。探索木は対局中の次の手番で再利用する
+pub struct MctsPlayer<'a> {
+    mcts: Mcts<'a>,
+    pub verbose: bool,
+}
+
+impl<'a> MctsPlayer<'a> {
+    pub fn new(mcts: Mcts<'a>) -> Self {
+        Self {
+            mcts,
+            verbose: false,
+        }
+    }
+}
+
+impl Player for MctsPlayer<'_> {
+    fn name(&self) -> String {
+        "mcts".to_string()
+    }
+    fn new_game(&mut self) {
+        self.mcts.clear();
+    }
+    fn choose_move(&mut self, game: &Game) -> MoveBit {
+        if self.verbose {
+            println!("思考中...");
+        }
+        let result = self.mcts.search(&game.board, &game.history);
+        let Some(mv) = result.best_move else {
+            return game.legal_moves()[0];
+        };
+        if self.verbose {
+            println!(
+                "MCTS 決定手: {} 勝率: {:.3} プレイアウト: {} (再利用した訪問回数: {}) 時間: {:.2}s PV: {}",
+                mv.to_string(),
+                (result.value + 1.0) / 2.0,
+                result.playouts,
+                result.reused_visits,
+                result.elapsed.as_secs_f32(),
+                pv_to_string(&result.pv)
+            );
+            for child in result.children.iter().take(5) {
+                println!(
+                    "  {:<12} 訪問回数: {:6} 勝率: {:.3} 事前確率: {:.3}",
+                    child.mv.to_string(),
+                    child.visits,
+                    (child.value + 1.0) / 2.0,
+                    child.prior
+                );
+            }
+        }
+        mv
+    }
+}
+
+#[test]
+fn test_ponder_hit_with_skill() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use crate::search::{SearchInfo, test_evaluate};
+    use crate::skill::SKILL_LEVELS;
+
+    //探索を始めた回数を数える
+    struct CountingObserver(AtomicUsize);
+    impl SearchObserver for CountingObserver {
+        fn on_start(&self) {
+            self.0.fetch_add(1, Ordering::Relaxed);
+        }
+        fn on_iteration(&self, _: &SearchInfo) {}
+        fn on_new_best(&self, _: &SearchInfo) {}
+        fn on_finished(&self, _: &SearchResult) {}
+    }
+
+    //スキルレベルで深さが制限されても、先読みで読み切っていればそのまま指す
+    let options = SearchOptions {
+        depth: 5,
+        skill: Some(SKILL_LEVELS[3]),
+        ..Default::default()
+    };
+    assert!(options.effective_depth() < options.depth);
+    let tt = TranspositionTable::new(1);
+    let observer = CountingObserver(AtomicUsize::new(0));
+    let mut player = SearchPlayer::new(options, &tt, &test_evaluate, &observer);
+    player.ponder = true;
+
+    let mut game = Game::new();
+    let mv = player.choose_move(&game);
+    game.apply(mv).unwrap();
+    let predicted = player.ponder_move.expect("読み筋に予想手がある");
+    player.ponder(&game, &Arc::new(AtomicBool::new(false)));
+    game.apply(predicted).unwrap();
+    player.choose_move(&game);
+    assert_eq!(observer.0.load(Ordering::Relaxed), 1);
+}
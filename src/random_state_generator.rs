@@ -1,8 +1,17 @@
+use rand::Rng;
 use rand::seq::IndexedRandom;
 
 use crate::bitboard::{Bitboard, MoveBit, MoveList};
 
 pub fn random_state_generator(num_turn: usize) -> (Bitboard, Option<u64>) {
+    random_state_generator_with_rng(num_turn, &mut rand::rng())
+}
+
+//乱数生成器を指定する(シードを固定すれば同じ局面になる)
+pub fn random_state_generator_with_rng(
+    num_turn: usize,
+    rng: &mut impl Rng,
+) -> (Bitboard, Option<u64>) {
     let mut board = Bitboard::new_initial();
     let mut prev_hash: Option<u64> = None;
     for _ in 0..num_turn {
@@ -12,7 +21,7 @@ pub fn random_state_generator(num_turn: usize) -> (Bitboard, Option<u64>) {
 
         let mut choosed_move: MoveBit;
         while {
-            choosed_move = (*moves.choose(&mut rand::rng()).unwrap()).clone();
+            choosed_move = (*moves.choose(rng).unwrap()).clone();
             board
                 .apply_force_with_check_illegal_move(choosed_move, prev_hash)
                 .is_err()
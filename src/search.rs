@@ -1,325 +1,794 @@
 use crate::bitboard::{Bitboard, MoveBit, MoveList};
-use crate::checkmate_search::{checkmate_in_one_move, find_mate_sequence};
-use crate::eval::{sigmoid, static_evaluation};
+use crate::checkmate_search::{
+    checkmate_in_one_move, find_mate_in_one_move, generate_threat_moves, is_brinkmate, is_reach,
+};
+use crate::dfpn::{DfpnSolver, MateResult};
+use crate::eval::static_evaluation;
+use crate::move_ordering::MoveOrdering;
+use crate::opening_book::BookOptions;
 use crate::search;
+use crate::search_observer::SearchObserver;
+use crate::search_params::SearchParams;
+use crate::search_trace::{SearchTrace, TraceEdge, TraceEvent};
+use crate::skill::Skill;
 use crate::snapshot::BoardSnapshot;
+use crate::transposition_table::{TTEntry, TTFlag, TranspositionTable};
 use Vec;
 use arrayvec::ArrayVec;
-use lru::LruCache;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 use std::ptr::null;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use std::{i16, i32, thread};
 
-const USE_CACHE: bool = true;
-
-const DRAW_SCORE: i16 = 0;
-const WIN_LOSE_SCORE: i16 = 30000;
-
-pub const EVAL_VALUE_MALTIPLIER: f32 = 100.0;
-
 fn evaluate_for_negamax(board: &mut Bitboard, prev_hash: Option<u64>) -> i16 {
     // eval_mon(board, prev_move)
     static_evaluation(board, prev_hash) * board.turn as i16
 }
 
+#[derive(Clone, Debug, Default)]
+pub struct SearchLine {
+    pub depth: usize,
+    pub score: i16,
+    pub pv: Vec<MoveBit>,
+}
+
 #[derive(Clone, Default)]
 pub struct SearchInfo {
     pub depth: usize,
     pub score: i16,
     pub pv: Vec<MoveBit>,
     pub nodes: usize,
+    pub lines: Vec<SearchLine>, //MultiPVの各読み筋(良い順)
 }
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
-pub enum TTFlag {
-    Exact,      // このスコアは真の評価値 (alpha < score < beta)
-    LowerBound, // このスコアは下限値 (score >= beta, betaカットで得られた)
-    UpperBound, // このBitboardは上限値 (score <= alpha, 有望な手が見つからなかった)
+//探索の最終結果
+#[derive(Clone, Debug, Default)]
+pub struct SearchResult {
+    pub best_move: Option<MoveBit>,
+    pub score: i16,
+    pub depth: usize, //読み切った深さ
+    pub pv: Vec<MoveBit>,
+    pub lines: Vec<SearchLine>, //MultiPVの各読み筋(良い順)。lines[0]が最善
+    pub nodes: usize,
+    pub elapsed: Duration,
+    pub from_book: bool, //探索せずに定跡から選んだ手
 }
 
-// 置換表に保存するデータ構造
-#[derive(Clone, Copy, Debug)]
-pub struct TTEntry {
-    score: i16,
-    depth: u8, // 保存したときの探索深さ
-    flag: TTFlag,
-    best_move: MoveBit, // その局面で見つかった最善手
+//この深さ未満ではアスピレーションウィンドウを使わない
+const ASPIRATION_MIN_DEPTH: usize = 3;
+
+//何ノードごとに共有ノード数への加算と時間切れ判定を行うか
+const NODE_FLUSH_INTERVAL: usize = 1024;
+
+//ルートからの探索アルゴリズム
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum SearchAlgorithm {
+    //PVS + アスピレーションウィンドウ
+    Pvs,
+    //幅0の窓での探索を繰り返して値を絞り込む
+    #[value(name = "mtdf")]
+    MtdF,
+    //枝刈り・置換表なしの全幅探索(他のアルゴリズムの検証用)
+    Negamax,
 }
 
-const MIN_MATE_SCORE: i16 = WIN_LOSE_SCORE - 1000;
+#[derive(Clone, Debug)]
+pub struct SearchOptions {
+    pub depth: usize,
+    pub threads: usize, //探索スレッド数(1ならヘルパースレッドなし)
+    pub time_limit: Option<Duration>,
+    pub multi_pv: usize,         //ルートで上位何手まで読み筋を求めるか
+    pub aspiration_window: i16,  //アスピレーションウィンドウの初期幅(0で無効)
+    pub quiescence_depth: usize, //末端で勝ち・受け・詰めろの手を延長する最大手数
+    pub algorithm: SearchAlgorithm,
+    pub params: SearchParams,
+    pub stop_signal: Option<Arc<AtomicBool>>, //外部から探索を止める(相手の手番中の先読みなど)
+    //同じ局面・深さなら常に同じ手・評価値・読み筋・ノード数を返す。
+    //1スレッドで時間や外部からの停止を無視し、置換表を空にしてから探索する
+    pub deterministic: bool,
+    pub trace: Option<Arc<Mutex<SearchTrace>>>, //メインスレッドの探索木を記録する
+    pub book: Option<BookOptions>,              //対局の序盤は定跡の手を探索せずに返す
+    //引き分けを避ける度合い。千日手・手数制限の引き分けを探索する側から見て-contemptと評価する(負なら引き分けを目指す)
+    pub contempt: i16,
+    pub moves_left: Option<usize>, //ルート局面から何手指すと手数制限で引き分けになるか
+    pub skill: Option<Skill>,      //棋力を落として指す
+}
 
-fn score_to_tt(score: i16, ply: usize) -> i16 {
-    if score > MIN_MATE_SCORE {
-        score + ply as i16
-    } else if score < -MIN_MATE_SCORE {
-        score - ply as i16
-    } else {
-        score
+impl SearchOptions {
+    //実際に探索する深さ(スキルレベルの上限を適用したもの)
+    pub fn effective_depth(&self) -> usize {
+        self.skill
+            .map_or(self.depth, |skill| self.depth.min(skill.depth))
     }
 }
 
-fn score_from_tt(score: i16, ply: usize) -> i16 {
-    if score > MIN_MATE_SCORE {
-        score - ply as i16
-    } else if score < -MIN_MATE_SCORE {
-        score + ply as i16
-    } else {
-        score
+impl Default for SearchOptions {
+    fn default() -> Self {
+        Self {
+            depth: 5,
+            threads: 1,
+            time_limit: None,
+            multi_pv: 1,
+            aspiration_window: 100,
+            quiescence_depth: 4,
+            algorithm: SearchAlgorithm::Pvs,
+            params: SearchParams::default(),
+            stop_signal: None,
+            deterministic: false,
+            trace: None,
+            book: None,
+            contempt: 0,
+            moves_left: None,
+            skill: None,
+        }
     }
 }
 
-const STATIC_EVAL_SORTING_DEPTH: usize = 2;
+//Lazy SMPのヘルパースレッドの深さずらし(スレッドごとに一部の深さを飛ばす)
+const SKIP_SIZE: [usize; 20] = [1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 3, 3, 4, 4, 4, 4, 4, 4, 4, 4];
+const SKIP_PHASE: [usize; 20] = [0, 1, 0, 1, 2, 3, 0, 1, 2, 3, 4, 5, 0, 1, 2, 3, 4, 5, 6, 7];
 
-pub fn alphabeta<F>(
-    board: &mut Bitboard,
+fn is_skipped_depth(helper_idx: usize, depth: usize) -> bool {
+    let i = helper_idx % SKIP_SIZE.len();
+    !((depth + SKIP_PHASE[i]) / SKIP_SIZE[i]).is_multiple_of(2)
+}
+
+//反復深化で完了した探索の結果
+#[derive(Clone)]
+struct CompletedSearch {
     depth: usize,
-    mut alpha: i16,
-    mut beta: i16,
-    tt: &mut LruCache<u64, TTEntry>,
-    route: &mut Vec<u64>,
-    // process: &mut Progress,
-    is_root: bool, // ★自分がルートノード（探索の起点）かを知るためのフラグ
-    shared_info: Arc<Mutex<SearchInfo>>, // ★情報共有のための構造体
-    prev_hash: Option<u64>,
-    evaluate: &F,
-    ply: usize,
-) -> (i16, Vec<MoveBit>)
+    lines: Vec<SearchLine>, //lines[0]が最善
+}
+
+//全探索スレッドで共有する状態
+#[derive(Default)]
+struct SharedSearchState {
+    info: Mutex<SearchInfo>,
+    stop: AtomicBool,
+    total_nodes: AtomicUsize,
+}
+
+//探索中のノードの位置。alphabetaと静止探索で子ノードへ受け渡す
+#[derive(Clone, Copy)]
+struct Node {
+    is_root: bool,
+    prev_hash: Option<u64>, //直前の局面のハッシュ
+    ply: usize,             //ルートからの手数
+    extensions: usize,      //ルートからこのノードまでに延長した回数
+}
+
+impl Node {
+    fn root(prev_hash: Option<u64>) -> Node {
+        Node {
+            is_root: true,
+            prev_hash,
+            ply: 0,
+            extensions: 0,
+        }
+    }
+
+    //この局面から1手進めた子ノード
+    fn child(&self, hash: u64) -> Node {
+        Node {
+            is_root: false,
+            prev_hash: Some(hash),
+            ply: self.ply + 1,
+            extensions: self.extensions,
+        }
+    }
+
+    //延長した子ノード
+    fn extended_child(&self, hash: u64, extension: usize) -> Node {
+        Node {
+            extensions: self.extensions + extension,
+            ..self.child(hash)
+        }
+    }
+}
+
+//探索スレッドごとの状態
+struct SearchWorker<'a, F> {
+    tt: &'a TranspositionTable,
+    evaluate: &'a F,
+    shared_info: &'a Mutex<SearchInfo>,
+    observer: &'a dyn SearchObserver,
+    stop: &'a AtomicBool,
+    total_nodes: &'a AtomicUsize,
+    nodes: usize, //total_nodesへ未加算のノード数
+    is_main: bool,
+    deadline: Option<Instant>,
+    stop_signal: Option<&'a AtomicBool>, //メインスレッドが見てstopへ伝える
+    multi_pv: usize,
+    aspiration_window: i16,
+    quiescence_depth: usize,
+    algorithm: SearchAlgorithm,
+    params: SearchParams,
+    pv_idx: usize,                     //MultiPVで何本目の読み筋を探索中か
+    excluded_root_moves: Vec<MoveBit>, //報告済みのためルートで探索しない手
+    ordering: Box<MoveOrdering>,
+    game_history: &'a [u64],  //ルート局面より前に対局で現れた局面
+    null_move_min_ply: usize, //null moveの検証探索中はこのply未満でnull moveを行わない
+    rep_hits: usize, //千日手・手数制限で評価を打ち切った回数(経路依存の結果を置換表に残さないため)
+    trace: Option<&'a Mutex<SearchTrace>>,
+    root_turn: i8,
+    contempt: i16,
+    moves_left: Option<usize>,
+    max_nodes: Option<usize>, //これを超えたら探索を打ち切る(メインスレッドが判定する)
+    skill: Option<Skill>,
+    noise_seed: u64,
+    dfpn: Option<DfpnSolver>, //最初に使うときに作り、以降は空にして使い回す
+}
+
+impl<'a, F> SearchWorker<'a, F>
 where
     F: Fn(&BoardSnapshot) -> i16 + Sync,
 {
-    // process.update(depth, board, tt.len());
-    let mut best_pv = Vec::new();
-    // canonical_board(&mut canonical_board_data);
-    let hash = board.to_compression_bod();
-    //千日手判定
-    if route.contains(&hash) {
-        return (DRAW_SCORE, Vec::new()); //引き分け評価
+    fn new(
+        tt: &'a TranspositionTable,
+        evaluate: &'a F,
+        shared: &'a SharedSearchState,
+        observer: &'a dyn SearchObserver,
+        is_main: bool,
+        deadline: Option<Instant>,
+    ) -> Self {
+        Self {
+            tt,
+            evaluate,
+            shared_info: &shared.info,
+            observer,
+            stop: &shared.stop,
+            total_nodes: &shared.total_nodes,
+            nodes: 0,
+            is_main,
+            deadline,
+            stop_signal: None,
+            multi_pv: 1,
+            aspiration_window: 0,
+            quiescence_depth: 0,
+            algorithm: SearchAlgorithm::Pvs,
+            params: SearchParams::default(),
+            pv_idx: 0,
+            excluded_root_moves: Vec::new(),
+            game_history: &[],
+            null_move_min_ply: 0,
+            rep_hits: 0,
+            ordering: Box::new(MoveOrdering::new()),
+            trace: None,
+            root_turn: 1,
+            contempt: 0,
+            moves_left: None,
+            max_nodes: None,
+            skill: None,
+            noise_seed: 0,
+            dfpn: None,
+        }
     }
-    route.push(hash);
 
-    //自己評価
-    if board.game_over() {
-        route.pop();
+    fn trace(&self, f: impl FnOnce(&mut SearchTrace)) {
+        if let Some(trace) = self.trace {
+            f(&mut trace.lock().unwrap());
+        }
+    }
+
+    fn count_node(&mut self) {
+        self.nodes += 1;
+        if self.nodes >= NODE_FLUSH_INTERVAL {
+            self.flush_nodes();
+            if self.is_main
+                && (self.deadline.is_some_and(|d| Instant::now() >= d)
+                    || self.max_nodes.is_some_and(|n| self.current_nodes() >= n)
+                    || self.stop_signal.is_some_and(|s| s.load(Ordering::Relaxed)))
+            {
+                self.stop.store(true, Ordering::Relaxed);
+            }
+        }
+    }
 
-        let win_sign = board.win_turn() * board.turn as i16;
-        let abs_socre = WIN_LOSE_SCORE - ply as i16;
+    fn flush_nodes(&mut self) {
+        self.total_nodes.fetch_add(self.nodes, Ordering::Relaxed);
+        self.nodes = 0;
+    }
 
-        let score = win_sign * abs_socre;
-        return (score, Vec::new());
+    fn stopped(&self) -> bool {
+        self.stop.load(Ordering::Relaxed)
     }
 
-    if depth == 0 {
-        route.pop();
-        let tsumi_result = find_mate_sequence(board, 1, prev_hash);
+    //探索経路上か対局中に既に現れた局面なら千日手(ルート局面自体は判定しない)
+    fn is_repetition(&self, hash: u64, route: &[u64], ply: usize) -> bool {
+        route.contains(&hash) || (ply > 0 && self.game_history.contains(&hash))
+    }
 
-        let static_score = evaluate(&board.to_snapshot(prev_hash));
-        //詰み探索を実行
-        let tsumi_found = if tsumi_result.is_some() { 1 } else { 0 };
+    //手番側から見た引き分けの評価値
+    fn draw_score(&self, turn: i8) -> i16 {
+        if turn == self.root_turn {
+            -self.contempt
+        } else {
+            self.contempt
+        }
+    }
 
-        let log_line = format!("{},{}", tsumi_found, board.to_compression_bod());
+    //手数制限に達した局面(ルート局面自体は判定しない)
+    fn is_move_limit(&self, ply: usize) -> bool {
+        ply > 0 && self.moves_left.is_some_and(|n| ply >= n)
+    }
 
-        //ファイル出力
-        // writeln!(log_file.lock().unwrap(), "{}", log_line).expect("ログを書き込めませんでした");
+    //静的評価。棋力を落とすときは局面ごとのノイズを加える
+    fn static_eval(&self, board: &Bitboard, prev_hash: Option<u64>) -> i16 {
+        let score = (self.evaluate)(&board.to_snapshot(prev_hash));
+        match &self.skill {
+            Some(skill) => {
+                let noise = skill.noise(board.to_compression_bod(), self.noise_seed);
+                let clamp = self.params.eval_clamp;
+                (score as i32 + noise as i32).clamp(-clamp as i32, clamp as i32) as i16
+            }
+            None => score,
+        }
+    }
+
+    fn current_nodes(&self) -> usize {
+        self.total_nodes.load(Ordering::Relaxed) + self.nodes
+    }
 
-        if let Some(mate_sequence) = tsumi_result {
-            // board.print_data();
-            // 詰みを発見！スコアを「勝ち」に格上げし、手順も返す
-            let mate_score = WIN_LOSE_SCORE - (mate_sequence.len() + ply) as i16;
-            return (mate_score, mate_sequence);
+    //ルートで最善手が更新された
+    fn report_new_best(&self, depth: usize, line: SearchLine) {
+        let mut info = self.shared_info.lock().unwrap();
+        if self.pv_idx == 0 {
+            info.score = line.score;
+            info.pv = line.pv.clone();
+            info.depth = depth;
         }
-        return (static_score, best_pv);
+        if self.multi_pv > 1 {
+            if self.pv_idx < info.lines.len() {
+                info.lines[self.pv_idx] = line;
+            } else {
+                info.lines.push(line);
+            }
+        }
+        info.nodes = self.current_nodes();
+        self.observer.on_new_best(&info);
     }
 
-    let original_alpha = alpha;
-    let original_beta = beta;
-    let mut best_move_from_tt: Option<MoveBit> = None;
-    //置換表参照
-    if USE_CACHE {
-        if let Some(entry) = tt.get(&hash) {
-            if entry.depth as usize >= depth {
-                let tt_score = score_from_tt(entry.score, ply);
+    //1つの深さの探索が終わった。linesは良い順
+    fn report_iteration(&self, depth: usize, lines: &[SearchLine]) {
+        if !self.is_main {
+            return;
+        }
+        let mut info = self.shared_info.lock().unwrap();
+        info.score = lines[0].score;
+        info.depth = depth;
+        info.pv = lines[0].pv.clone();
+        if self.multi_pv > 1 {
+            info.lines = lines.to_vec();
+        }
+        info.nodes = self.current_nodes();
+        self.observer.on_iteration(&info);
+    }
 
-                match entry.flag {
-                    TTFlag::Exact => {
-                        route.pop();
-                        return (tt_score, vec![entry.best_move]);
+    fn alphabeta(
+        &mut self,
+        board: &mut Bitboard,
+        depth: usize,
+        alpha: i16,
+        beta: i16,
+        route: &mut Vec<u64>,
+        node: Node,
+    ) -> (i16, Vec<MoveBit>) {
+        //深さ0は静止探索のノードとして記録する
+        if self.trace.is_none() || depth == 0 {
+            return self.alphabeta_node(board, depth, alpha, beta, route, node);
+        }
+        self.trace(|t| t.enter(node.ply, depth, alpha, beta, false));
+        let (score, pv) = self.alphabeta_node(board, depth, alpha, beta, route, node);
+        let score_if_done = (!self.stopped()).then_some(score);
+        self.trace(|t| t.exit(score_if_done, pv.first().copied()));
+        (score, pv)
+    }
+
+    fn alphabeta_node(
+        &mut self,
+        board: &mut Bitboard,
+        depth: usize,
+        mut alpha: i16,
+        mut beta: i16,
+        route: &mut Vec<u64>,
+        node: Node,
+    ) -> (i16, Vec<MoveBit>) {
+        if depth == 0 {
+            return self.quiescence(board, alpha, beta, route, node, self.quiescence_depth);
+        }
+        let Node {
+            is_root, // ★自分がルートノード（探索の起点）かを知るためのフラグ
+            prev_hash,
+            ply,
+            ..
+        } = node;
+
+        self.count_node();
+        //停止要求が来ていたら結果を捨てて戻る
+        if self.stopped() {
+            return (0, Vec::new());
+        }
+
+        let mut best_pv = Vec::new();
+        // canonical_board(&mut canonical_board_data);
+        let hash = board.to_compression_bod();
+        //千日手判定
+        if self.is_repetition(hash, route, ply) {
+            self.rep_hits += 1;
+            self.trace(|t| t.event(TraceEvent::Repetition));
+            return (self.draw_score(board.turn), Vec::new()); //引き分け評価
+        }
+        route.push(hash);
+        let rep_hits_before = self.rep_hits;
+
+        //自己評価
+        if board.game_over() {
+            route.pop();
+
+            let win_sign = board.win_turn() * board.turn as i16;
+            let abs_socre = self.params.win_lose_score - ply as i16;
+
+            let score = win_sign * abs_socre;
+            return (score, Vec::new());
+        }
+        if self.is_move_limit(ply) {
+            route.pop();
+            self.rep_hits += 1;
+            return (self.draw_score(board.turn), Vec::new());
+        }
+
+        let original_alpha = alpha;
+        let original_beta = beta;
+        let mut best_move_from_tt: Option<MoveBit> = None;
+        //除外手があるルートでは置換表の結果をそのまま使えない
+        let is_excluding = is_root && !self.excluded_root_moves.is_empty();
+        //置換表参照
+        if self.params.use_cache
+            && let Some(entry) = self.tt.probe(hash)
+        {
+            if entry.depth as usize >= depth && !is_excluding {
+                let tt_score = self.params.score_from_tt(entry.score, ply);
+                self.trace(|t| {
+                    t.event(TraceEvent::TTHit {
+                        flag: entry.flag,
+                        depth: entry.depth,
+                        score: tt_score,
+                        cutoff: match entry.flag {
+                            TTFlag::Exact => true,
+                            TTFlag::LowerBound => tt_score >= beta,
+                            TTFlag::UpperBound => tt_score <= alpha,
+                        },
+                    })
+                });
+
+                let cutoff = match entry.flag {
+                    TTFlag::Exact => true,
+                    TTFlag::LowerBound => {
+                        alpha = alpha.max(tt_score);
+                        alpha >= beta
                     }
-                    TTFlag::LowerBound => alpha = alpha.max(tt_score),
-                    TTFlag::UpperBound => beta = beta.min(tt_score),
-                }
-                if alpha >= beta {
+                    TTFlag::UpperBound => {
+                        beta = beta.min(tt_score);
+                        alpha >= beta
+                    }
+                };
+                if cutoff {
                     route.pop();
-                    return (tt_score, vec![entry.best_move]);
+                    //読み筋は置換表の最善手をたどって作る
+                    let mut pv = Vec::new();
+                    self.extend_pv_from_tt(board, &mut pv, route, node, depth);
+                    return (tt_score, pv);
                 }
             }
             best_move_from_tt = Some(entry.best_move);
         }
-    }
 
-    let mut moves = MoveList::new();
-    board.generate_legal_moves(&mut moves);
+        //深いノードではdf-pnで短い詰みを探す
+        if self.params.dfpn && depth >= self.params.dfpn_min_depth && !is_excluding {
+            let mut history = self.game_history.to_vec();
+            history.extend_from_slice(&route[..route.len() - 1]);
+            let dfpn_nodes = self.params.dfpn_nodes;
+            let solver = self.dfpn.get_or_insert_with(|| DfpnSolver::new(dfpn_nodes));
+            solver.clear();
+            let start_nodes = solver.nodes();
+            let result = solver.solve(board, &history, self.params.dfpn_max_depth);
+            self.nodes += solver.nodes() - start_nodes;
+            if let MateResult::Mate(pv) = result {
+                self.trace(|t| t.event(TraceEvent::DfpnMate { len: pv.len() }));
+                let score = self.params.win_lose_score - (ply + pv.len()) as i16;
+                if self.params.use_cache {
+                    self.tt.store(
+                        hash,
+                        TTEntry {
+                            best_move: pv[0],
+                            score: self.params.score_to_tt(score, ply),
+                            depth: depth as u8,
+                            flag: TTFlag::Exact,
+                        },
+                    );
+                }
+                route.pop();
+                return (score, pv);
+            }
+        }
+
+        //枝刈りはPVノード以外で、相手に1手勝ちがない(詰めろでない)局面でのみ行う
+        let is_pv_node = beta as i32 - alpha as i32 > 1;
+        let mut futility_value: Option<i16> = None;
+        if !is_root
+            && !is_pv_node
+            && self.params.uses_pruning()
+            && !self.params.is_mate_score(alpha)
+            && !self.params.is_mate_score(beta)
+            && !is_reach(board, prev_hash)
+        {
+            let static_eval = self.static_eval(board, prev_hash);
 
-    let is_sort = is_root || depth >= STATIC_EVAL_SORTING_DEPTH;
+            //Razoring: 静的評価がalphaを大きく下回るなら静止探索で確かめて打ち切る
+            if self.params.razoring
+                && depth <= self.params.razoring_max_depth
+                && (static_eval as i32 + self.params.razoring_margin as i32 * depth as i32)
+                    <= alpha as i32
+            {
+                route.pop();
+                self.trace(|t| t.set_next_edge(TraceEdge::Verify, 0));
+                let (q, _) =
+                    self.quiescence(board, alpha, alpha + 1, route, node, self.quiescence_depth);
+                if self.stopped() || q <= alpha {
+                    self.trace(|t| t.event(TraceEvent::Razoring { static_eval }));
+                    return (q, Vec::new());
+                }
+                route.push(hash);
+            }
 
-    if is_sort {
-        moves.sort_by_cached_key(|&mv| {
-            let move_score: i16;
-            if Some(mv) == best_move_from_tt {
-                move_score = i16::MAX;
-            } else {
-                match board.apply_force_with_check_illegal_move(mv, prev_hash) {
-                    Ok(()) => {
-                        move_score = -evaluate(&board.to_snapshot(Some(hash)));
-                        board.undo_force(mv);
+            //Null move: 手番をパスしても相手がbetaを下回らせられないなら打ち切る
+            //直前がパスのとき(連続パス)と検証探索中は行わない
+            if self.params.null_move
+                && depth >= self.params.null_move_min_depth
+                && ply >= self.null_move_min_ply
+                && static_eval >= beta
+                && self.ordering.previous_move(ply).is_some()
+            {
+                let reduction = self.params.null_move_reduction;
+                self.ordering.set_played_move(ply, None);
+                board.turn_change();
+                self.trace(|t| t.set_next_edge(TraceEdge::NullMove, 0));
+                let (s, _) = self.alphabeta(
+                    board,
+                    depth.saturating_sub(1 + reduction),
+                    -beta,
+                    -beta + 1,
+                    route,
+                    node.child(hash),
+                );
+                board.turn_change();
+                if self.stopped() {
+                    route.pop();
+                    return (0, Vec::new());
+                }
+
+                let null_score = -s;
+                if null_score >= beta {
+                    //パスで得た詰みの評価値は信用しない
+                    let null_score = if self.params.is_mate_score(null_score) {
+                        beta
+                    } else {
+                        null_score
+                    };
+                    if depth < self.params.null_move_verify_depth {
+                        route.pop();
+                        self.trace(|t| t.event(TraceEvent::NullMove { score: null_score }));
+                        return (null_score, Vec::new());
                     }
-                    Err(()) => {
-                        move_score = i16::MIN;
+
+                    //深いノードではツークツワンクに備えてパスなしの浅い探索で確かめる
+                    let verify_depth = depth - reduction.min(depth - 1);
+                    let saved_min_ply = self.null_move_min_ply;
+                    self.null_move_min_ply = ply + 3 * verify_depth / 4;
+                    route.pop();
+                    self.trace(|t| t.set_next_edge(TraceEdge::Verify, 0));
+                    let (v, _) = self.alphabeta(board, verify_depth, beta - 1, beta, route, node);
+                    self.null_move_min_ply = saved_min_ply;
+                    if self.stopped() || v >= beta {
+                        self.trace(|t| t.event(TraceEvent::NullMove { score: null_score }));
+                        return (null_score, Vec::new());
                     }
+                    route.push(hash);
                 }
             }
 
-            //降順にするため反転
-            -move_score
-        });
-    } else {
-        if let Some(tt_move) = best_move_from_tt {
-            if let Some(pos) = moves.iter().position(|m| *m == tt_move) {
-                let m = moves.remove(pos);
-                moves.insert(0, m);
+            //Futility pruning: 残り深さが浅く静的評価にマージンを足してもalphaに届かないなら静かな手を読まない
+            if self.params.futility && depth <= self.params.futility_max_depth {
+                let value = static_eval as i32 + self.params.futility_margin as i32 * depth as i32;
+                if value <= alpha as i32 {
+                    futility_value = Some(value as i16);
+                    self.trace(|t| {
+                        t.event(TraceEvent::Futility {
+                            value: value as i16,
+                        })
+                    });
+                }
             }
         }
-    }
 
-    let mut best_score = i16::MIN;
-    let mut best_move: Option<MoveBit> = None;
+        let mut moves = MoveList::new();
+        board.generate_legal_moves(&mut moves);
 
-    for (i, &mv) in moves.iter().enumerate() {
-        //手を実行
-        if board
-            .apply_force_with_check_illegal_move(mv, prev_hash)
-            .is_err()
-        {
-            continue;
-        }
+        let is_sort = is_root || depth >= self.params.static_eval_sorting_depth;
 
-        let score;
-        let can_lmr = depth >= 3 && i >= 4 && !is_root && !checkmate_in_one_move(board, Some(hash));
+        if is_sort {
+            let evaluate = self.evaluate;
+            let ordering = &self.ordering;
+            let turn_idx = board.get_turn_idx();
+            moves.sort_by_cached_key(|&mv| {
+                let move_score: i16;
+                if Some(mv) == best_move_from_tt {
+                    move_score = i16::MAX;
+                } else if let Some(rank) = ordering.killer_rank(mv, turn_idx, ply) {
+                    //キラー手・カウンター手は評価値より優先する
+                    move_score = i16::MAX - 1 - rank as i16;
+                } else {
+                    match board.apply_force_with_check_illegal_move(mv, prev_hash) {
+                        Ok(()) => {
+                            move_score = -evaluate(&board.to_snapshot(Some(hash)));
+                            board.undo_force(mv);
+                        }
+                        Err(()) => {
+                            move_score = i16::MIN;
+                        }
+                    }
+                }
 
-        let mut child_pv;
-        if i == 0 || !is_sort {
-            //その手ができた場合
-            let (s, pv) = alphabeta(
-                board,
-                depth - 1,
-                -beta,
-                -alpha,
-                tt,
-                route,
-                // process,
-                false,
-                shared_info.clone(),
-                Some(hash),
-                evaluate,
-                ply + 1,
-            );
-            score = -s;
-            child_pv = pv;
+                //降順にするため反転(i16::MINを反転しても溢れないようi32で)
+                -(move_score as i32)
+            });
         } else {
-            let mut reduction = 0;
-            if can_lmr {
-                reduction = 1;
-                if i >= 10 {
-                    reduction = 2;
-                }
+            //浅いノードは評価関数を呼ばず、置換表の手・キラー手・カウンター手・履歴の順に並べる
+            let ordering = &self.ordering;
+            moves.sort_by_cached_key(|&mv| {
+                -ordering.score(mv, board.get_turn_idx(), ply, best_move_from_tt)
+            });
+        }
 
-                if i >= 25 {
-                    reduction = 3;
-                }
+        let mut best_score = i16::MIN;
+        let mut best_move: Option<MoveBit> = None;
 
-                //残り深さが0にならないようにする
-                if depth <= 1 + reduction {
-                    reduction = depth - 2;
-                }
+        if is_excluding {
+            moves.retain(|mv| !self.excluded_root_moves.contains(mv));
+        }
+
+        for (i, &mv) in moves.iter().enumerate() {
+            //手を実行
+            if board
+                .apply_force_with_check_illegal_move(mv, prev_hash)
+                .is_err()
+            {
+                continue;
             }
+            self.ordering.set_played_move(ply, Some(mv));
 
-            let (s, _) = alphabeta(
-                board,
-                depth - 1 - reduction,
-                -alpha - 1,
-                -alpha,
-                tt,
-                route,
-                false,
-                shared_info.clone(),
-                Some(hash),
-                evaluate,
-                ply + 1,
+            //勝ちの手と詰めろをかける手は枝刈りしない
+            if let Some(value) = futility_value
+                && i > 0
+                && !board.game_over()
+                && !is_reach(board, Some(hash))
+            {
+                board.undo_force(mv);
+                best_score = best_score.max(value);
+                self.trace(|t| t.event(TraceEvent::FutilityPruned { mv }));
+                continue;
+            }
+
+            //必至をかける手は静止探索に落とさず1手延長する(1つの経路で上限まで)
+            let extension = usize::from(
+                self.params.brinkmate_extension
+                    && depth == 1
+                    && node.extensions < self.params.brinkmate_max_extensions
+                    && !board.game_over()
+                    && is_reach(board, Some(hash))
+                    && is_brinkmate(board, Some(hash), |h| self.is_repetition(h, route, ply + 2)),
             );
-            let mut temp_score = -s;
+            let child_depth = depth - 1 + extension;
+            let child = node.extended_child(hash, extension);
+            if extension > 0 {
+                self.trace(|t| t.event(TraceEvent::Extension { mv }));
+            }
+
+            let score;
+            let can_lmr = depth >= self.params.lmr_min_depth
+                && self.params.lmr_reduction(i) > 0
+                && !is_root
+                && !checkmate_in_one_move(board, Some(hash));
+
+            let mut child_pv;
+            if i == 0 || !is_sort {
+                //その手ができた場合
+                self.trace(|t| t.set_next_edge(TraceEdge::Move(mv), 0));
+                let (s, pv) = self.alphabeta(board, child_depth, -beta, -alpha, route, child);
+                score = -s;
+                child_pv = pv;
+            } else {
+                let mut reduction = 0;
+                if can_lmr {
+                    reduction = self.params.lmr_reduction(i);
 
-            if temp_score > alpha && reduction > 0 {
-                let (s, _) = alphabeta(
+                    //残り深さが0にならないようにする
+                    if child_depth <= reduction {
+                        reduction = child_depth.saturating_sub(1);
+                    }
+                }
+
+                self.trace(|t| t.set_next_edge(TraceEdge::Move(mv), reduction));
+                let (s, _) = self.alphabeta(
                     board,
-                    depth - 1,
+                    child_depth - reduction,
                     -alpha - 1,
                     -alpha,
-                    tt,
                     route,
-                    false,
-                    shared_info.clone(),
-                    Some(hash),
-                    evaluate,
-                    ply + 1,
+                    child,
                 );
-                temp_score = -s;
+                let mut temp_score = -s;
+
+                if temp_score > alpha && reduction > 0 {
+                    self.trace(|t| t.set_next_edge(TraceEdge::Move(mv), 0));
+                    let (s, _) =
+                        self.alphabeta(board, child_depth, -alpha - 1, -alpha, route, child);
+                    temp_score = -s;
+                }
+
+                if temp_score > alpha && temp_score < beta {
+                    self.trace(|t| t.set_next_edge(TraceEdge::Move(mv), 0));
+                    let (s, pv) = self.alphabeta(board, child_depth, -beta, -alpha, route, child);
+                    score = -s;
+                    child_pv = pv;
+                } else {
+                    score = temp_score;
+                    child_pv = Vec::new();
+                }
             }
+            board.undo_force(mv); //Bitboardに戻す
 
-            if temp_score > alpha && temp_score < beta {
-                let (s, pv) = alphabeta(
-                    board,
-                    depth - 1,
-                    -beta,
-                    -alpha,
-                    tt,
-                    route,
-                    // process,
-                    false,
-                    shared_info.clone(),
-                    Some(hash),
-                    evaluate,
-                    ply + 1,
-                );
-                score = -s;
-                child_pv = pv;
-            } else {
-                score = temp_score;
-                child_pv = Vec::new();
+            //停止後のスコアは信用できないので置換表にも残さない
+            if self.stopped() {
+                route.pop();
+                return (0, Vec::new());
             }
-        }
-        board.undo_force(mv); //Bitboardに戻す
-        if best_score < score {
-            best_score = score;
-            best_move = Some(mv);
-            best_pv.clear();
-            best_pv.push(mv);
-            best_pv.append(&mut child_pv);
-            if is_root {
-                let mut info = shared_info.lock().unwrap();
-                info.score = best_score;
-                info.pv = best_pv.clone();
-                info.depth = depth;
-                // info.nodes = process.nodes_searched;
+
+            if best_score < score {
+                best_score = score;
+                best_move = Some(mv);
+                best_pv.clear();
+                best_pv.push(mv);
+                best_pv.append(&mut child_pv);
+                if is_root {
+                    if self.is_main {
+                        self.report_new_best(
+                            depth,
+                            SearchLine {
+                                depth,
+                                score: best_score,
+                                pv: best_pv.clone(),
+                            },
+                        );
+                    }
+                }
+            }
+            alpha = alpha.max(best_score);
+            if alpha >= beta {
+                self.ordering
+                    .update_cutoff(mv, board.get_turn_idx(), ply, depth, &moves[..i]);
+                self.trace(|t| t.event(TraceEvent::BetaCutoff { mv, index: i }));
+                break; //beta cut
             }
         }
-        alpha = alpha.max(best_score);
-        if alpha >= beta {
-            break; //beta cut
-        }
-    }
 
-    if USE_CACHE {
-        if let Some(mv) = best_move {
+        if self.params.use_cache
+            && !is_excluding
+            && let Some(mv) = best_move
+        {
             let flag = if best_score <= original_alpha {
                 TTFlag::UpperBound
             } else if best_score >= original_beta {
@@ -328,217 +797,1091 @@ where
                 TTFlag::Exact
             };
 
-            let tt_socre_to_save = score_to_tt(best_score, ply);
+            let tt_socre_to_save = self.params.score_to_tt(best_score, ply);
 
+            //千日手の判定を含む結果は経路によって変わるので、手の並べ替えにだけ使えるよう深さ0で残す
+            let depends_on_route = self.rep_hits != rep_hits_before;
             let new_entry = TTEntry {
                 best_move: mv,
                 score: tt_socre_to_save,
-                depth: depth as u8,
+                depth: if depends_on_route { 0 } else { depth as u8 },
                 flag,
             };
-            tt.put(hash, new_entry);
+            self.tt.store(hash, new_entry);
         }
+
+        route.pop(); // 探索パスから除去して戻る
+        (best_score, best_pv)
     }
 
-    if 29900 < best_score {
-        let log_line = format!("{},{}", 1, board.to_compression_bod());
-        //ファイル出力
-        // writeln!(log_file.lock().unwrap(), "{}", log_line).expect("ログを書き込めませんでした");
+    //末端で勝ち・受け・詰めろの手だけを延長し、静かな局面になってから評価する
+    fn quiescence(
+        &mut self,
+        board: &mut Bitboard,
+        alpha: i16,
+        beta: i16,
+        route: &mut Vec<u64>,
+        node: Node,
+        qdepth: usize,
+    ) -> (i16, Vec<MoveBit>) {
+        if self.trace.is_none() {
+            return self.quiescence_node(board, alpha, beta, route, node, qdepth);
+        }
+        self.trace(|t| t.enter(node.ply, qdepth, alpha, beta, true));
+        let (score, pv) = self.quiescence_node(board, alpha, beta, route, node, qdepth);
+        let score_if_done = (!self.stopped()).then_some(score);
+        self.trace(|t| t.exit(score_if_done, pv.first().copied()));
+        (score, pv)
     }
 
-    route.pop(); // 探索パスから除去して戻る
-    (best_score, best_pv)
-}
+    fn quiescence_node(
+        &mut self,
+        board: &mut Bitboard,
+        mut alpha: i16,
+        beta: i16,
+        route: &mut Vec<u64>,
+        node: Node,
+        qdepth: usize,
+    ) -> (i16, Vec<MoveBit>) {
+        let Node { prev_hash, ply, .. } = node;
+        self.count_node();
+        if self.stopped() {
+            return (0, Vec::new());
+        }
 
-pub fn mtd_f<F>(
-    board: &mut Bitboard,
-    f: i16,
-    depth: usize,
-    tt: Arc<Mutex<LruCache<u64, TTEntry>>>,
-    prev_hash: Option<u64>,
-    evaluate: &F,
-) -> (i16, Option<MoveBit>)
-where
-    F: Fn(&BoardSnapshot) -> i16 + Sync,
-{
-    let shared_info = Arc::new(Mutex::new(SearchInfo::default()));
-    let info_clone_for_ui = shared_info.clone();
-    let tt_for_thread = Arc::clone(&tt);
-    let mut vidro_for_search = board.clone();
-
-    std::thread::scope(|s| {
-        let search_thread = s.spawn(move || {
-            let mut prev_socre = f;
-            let mut sequence: Vec<MoveBit> = Vec::new();
-
-            for depth_level in 1..=depth {
-                let mut tt_guard = tt_for_thread.lock().unwrap();
-                let mut g = prev_socre;
-                let mut upper_bound = i16::MAX;
-                let mut lower_bound = i16::MIN;
-                while lower_bound < upper_bound {
-                    let beta: i16;
-                    if g == lower_bound {
-                        beta = g + 1;
-                    } else {
-                        beta = g;
+        let hash = board.to_compression_bod();
+        //千日手判定
+        if self.is_repetition(hash, route, ply) {
+            self.rep_hits += 1;
+            self.trace(|t| t.event(TraceEvent::Repetition));
+            return (self.draw_score(board.turn), Vec::new());
+        }
+
+        if board.game_over() {
+            let win_sign = board.win_turn() * board.turn as i16;
+            return (
+                win_sign * (self.params.win_lose_score - ply as i16),
+                Vec::new(),
+            );
+        }
+        if self.is_move_limit(ply) {
+            self.rep_hits += 1;
+            return (self.draw_score(board.turn), Vec::new());
+        }
+
+        //1手で勝てる
+        if let Some(mv) = find_mate_in_one_move(board, prev_hash) {
+            self.trace(|t| t.event(TraceEvent::MateInOne { mv }));
+            return (self.params.win_lose_score - (ply + 1) as i16, vec![mv]);
+        }
+
+        let static_score = self.static_eval(board, prev_hash);
+        if qdepth == 0 {
+            //必至なら受けがなく、次の相手の手で負け
+            if self.params.brinkmate_eval
+                && is_reach(board, prev_hash)
+                && is_brinkmate(board, prev_hash, |h| self.is_repetition(h, route, ply + 1))
+            {
+                self.trace(|t| t.event(TraceEvent::Brinkmate));
+                return (-(self.params.win_lose_score - (ply + 2) as i16), Vec::new());
+            }
+            return (static_score, Vec::new());
+        }
+
+        //相手が次の手で勝てる状態(詰めろ)ならstand patせず受けの手を全て読む
+        let is_threatened = is_reach(board, prev_hash);
+        let mut best_score;
+        let mut moves = MoveList::new();
+        if is_threatened {
+            //受けられなければ次の相手の手で負け
+            best_score = -(self.params.win_lose_score - (ply + 2) as i16);
+            board.generate_legal_moves(&mut moves);
+        } else {
+            best_score = static_score;
+            if best_score >= beta {
+                self.trace(|t| t.event(TraceEvent::StandPat { score: best_score }));
+                return (best_score, Vec::new());
+            }
+            alpha = alpha.max(best_score);
+            moves = generate_threat_moves(board, prev_hash);
+        }
+
+        let mut best_pv = Vec::new();
+        route.push(hash);
+        for mv in moves {
+            if board
+                .apply_force_with_check_illegal_move(mv, prev_hash)
+                .is_err()
+            {
+                continue;
+            }
+            //詰めろを受けていない手は読まない
+            if is_threatened && checkmate_in_one_move(board, Some(hash)) {
+                board.undo_force(mv);
+                continue;
+            }
+            self.trace(|t| t.set_next_edge(TraceEdge::Move(mv), 0));
+            let (s, mut child_pv) =
+                self.quiescence(board, -beta, -alpha, route, node.child(hash), qdepth - 1);
+            board.undo_force(mv);
+            if self.stopped() {
+                route.pop();
+                return (0, Vec::new());
+            }
+
+            let score = -s;
+            if best_score < score {
+                best_score = score;
+                best_pv.clear();
+                best_pv.push(mv);
+                best_pv.append(&mut child_pv);
+            }
+            alpha = alpha.max(best_score);
+            if alpha >= beta {
+                break;
+            }
+        }
+        route.pop();
+        (best_score, best_pv)
+    }
+
+    //前回の深さの評価値の周りの狭い窓で探索し、外れたら窓を広げて再探索する
+    fn aspiration_search(
+        &mut self,
+        board: &mut Bitboard,
+        depth: usize,
+        max_depth: usize,
+        prev_score: Option<i16>,
+        prev_hash: Option<u64>,
+    ) -> (i16, Vec<MoveBit>) {
+        let full_alpha = i16::MIN as i32 + max_depth as i32;
+        let full_beta = i16::MAX as i32 - max_depth as i32;
+        let mut delta = self.aspiration_window as i32;
+
+        let (mut alpha, mut beta) = match prev_score {
+            //詰みのスコアの周りでは窓を狭めない
+            Some(score)
+                if delta > 0
+                    && depth >= ASPIRATION_MIN_DEPTH
+                    && !self.params.is_mate_score(score) =>
+            {
+                (
+                    (score as i32 - delta).max(full_alpha),
+                    (score as i32 + delta).min(full_beta),
+                )
+            }
+            _ => (full_alpha, full_beta),
+        };
+
+        loop {
+            let mut route = Vec::new();
+            let (score, pv) = self.alphabeta(
+                board,
+                depth,
+                alpha as i16,
+                beta as i16,
+                &mut route,
+                Node::root(prev_hash),
+            );
+            if self.stopped() {
+                return (score, pv);
+            }
+
+            if score as i32 <= alpha && alpha > full_alpha {
+                //fail-low: 下側に広げる
+                beta = (alpha + beta) / 2;
+                alpha = if self.params.is_mate_score(score) {
+                    full_alpha
+                } else {
+                    (score as i32 - delta).max(full_alpha)
+                };
+            } else if score as i32 >= beta && beta < full_beta {
+                //fail-high: 上側に広げる
+                beta = if self.params.is_mate_score(score) {
+                    full_beta
+                } else {
+                    (score as i32 + delta).min(full_beta)
+                };
+            } else {
+                return (score, pv);
+            }
+            delta += delta / 2;
+        }
+    }
+
+    //MTD(f): 前回の評価値を初期値に幅0の窓で探索を繰り返し、上下の境界が一致するまで絞り込む
+    //各パスの結果は置換表に残るので、同じ深さの再探索はほとんど置換表で打ち切られる
+    fn mtdf_search(
+        &mut self,
+        board: &mut Bitboard,
+        depth: usize,
+        max_depth: usize,
+        first_guess: i16,
+        prev_hash: Option<u64>,
+    ) -> (i16, Vec<MoveBit>) {
+        let mut lower_bound = i16::MIN as i32 + max_depth as i32;
+        let mut upper_bound = i16::MAX as i32 - max_depth as i32;
+        let mut g = first_guess as i32;
+        let mut best_pv = Vec::new();
+
+        while lower_bound < upper_bound {
+            let beta = if g == lower_bound { g + 1 } else { g };
+            let mut route = Vec::new();
+            let (score, pv) = self.alphabeta(
+                board,
+                depth,
+                (beta - 1) as i16,
+                beta as i16,
+                &mut route,
+                Node::root(prev_hash),
+            );
+            if self.stopped() {
+                return (score, best_pv);
+            }
+            g = score as i32;
+            if g < beta {
+                upper_bound = g;
+                //全ての手が失敗したパスの手順は最善とは限らないので、fail-highの手順がまだ無いときだけ使う
+                if best_pv.is_empty() {
+                    best_pv = pv;
+                }
+            } else {
+                lower_bound = g;
+                best_pv = pv;
+            }
+        }
+        (g as i16, best_pv)
+    }
+
+    //置換表の最善手をたどって読み筋をmax_len手まで伸ばす
+    //置換表の手は合法手か・千日手にならないかを確かめてから使い、指せない手があればそこで切る
+    //routeはこの局面の手前までの探索経路
+    fn extend_pv_from_tt(
+        &self,
+        board: &mut Bitboard,
+        pv: &mut Vec<MoveBit>,
+        route: &[u64],
+        node: Node,
+        max_len: usize,
+    ) {
+        let mut played: Vec<MoveBit> = Vec::new();
+        let mut pv_route = Vec::new();
+        let mut prev_hash = node.prev_hash;
+        let mut i = 0;
+        while i < max_len && !board.game_over() {
+            let hash = board.to_compression_bod();
+            //千日手になる手順は伸ばさない
+            if self.is_repetition(hash, route, node.ply + i) || pv_route.contains(&hash) {
+                break;
+            }
+            pv_route.push(hash);
+
+            let mv = match pv.get(i) {
+                Some(&mv) => mv,
+                None => {
+                    let Some(entry) = self.tt.probe(hash) else {
+                        break;
+                    };
+                    let mut moves = MoveList::new();
+                    board.generate_legal_moves(&mut moves);
+                    if !moves.contains(&entry.best_move) {
+                        break;
                     }
-                    let mut route = Vec::new();
-                    (g, sequence) = alphabeta(
-                        &mut vidro_for_search,
-                        depth_level,
-                        beta - 1,
-                        beta,
-                        &mut tt_guard,
-                        &mut route,
-                        true,
-                        shared_info.clone(),
-                        prev_hash,
-                        evaluate,
-                        0,
+                    entry.best_move
+                }
+            };
+            if board
+                .apply_force_with_check_illegal_move(mv, prev_hash)
+                .is_err()
+            {
+                pv.truncate(i);
+                break;
+            }
+            if i >= pv.len() {
+                pv.push(mv);
+            }
+            played.push(mv);
+            prev_hash = Some(hash);
+            i += 1;
+        }
+        for &mv in played.iter().rev() {
+            board.undo_force(mv);
+        }
+    }
+
+    //枝刈りなしの全幅探索。末端は静止探索を全幅の窓で呼ぶのでalphabetaと同じ値になる
+    fn negamax(
+        &mut self,
+        board: &mut Bitboard,
+        depth: usize,
+        route: &mut Vec<u64>,
+        node: Node,
+    ) -> (i16, Vec<MoveBit>) {
+        if depth == 0 {
+            return self.quiescence(
+                board,
+                i16::MIN + 1,
+                i16::MAX,
+                route,
+                node,
+                self.quiescence_depth,
+            );
+        }
+        let Node {
+            is_root,
+            prev_hash,
+            ply,
+            ..
+        } = node;
+
+        self.count_node();
+        if self.stopped() {
+            return (0, Vec::new());
+        }
+
+        let hash = board.to_compression_bod();
+        //千日手判定
+        if self.is_repetition(hash, route, ply) {
+            self.rep_hits += 1;
+            return (self.draw_score(board.turn), Vec::new());
+        }
+        if board.game_over() {
+            let win_sign = board.win_turn() * board.turn as i16;
+            return (
+                win_sign * (self.params.win_lose_score - ply as i16),
+                Vec::new(),
+            );
+        }
+        if self.is_move_limit(ply) {
+            self.rep_hits += 1;
+            return (self.draw_score(board.turn), Vec::new());
+        }
+
+        let mut moves = MoveList::new();
+        board.generate_legal_moves(&mut moves);
+        if is_root {
+            moves.retain(|mv| !self.excluded_root_moves.contains(mv));
+        }
+
+        route.push(hash);
+        let mut best_score = i16::MIN;
+        let mut best_pv = Vec::new();
+        for mv in moves {
+            if board
+                .apply_force_with_check_illegal_move(mv, prev_hash)
+                .is_err()
+            {
+                continue;
+            }
+            let (s, mut child_pv) = self.negamax(board, depth - 1, route, node.child(hash));
+            board.undo_force(mv);
+            if self.stopped() {
+                route.pop();
+                return (0, Vec::new());
+            }
+
+            let score = -s;
+            if best_score < score {
+                best_score = score;
+                best_pv.clear();
+                best_pv.push(mv);
+                best_pv.append(&mut child_pv);
+                if is_root && self.is_main {
+                    self.report_new_best(
+                        depth,
+                        SearchLine {
+                            depth,
+                            score,
+                            pv: best_pv.clone(),
+                        },
                     );
-                    if g < beta {
-                        upper_bound = g;
-                    } else {
-                        lower_bound = g;
-                    }
                 }
-                prev_socre = g;
             }
-            (prev_socre, sequence.get(0).map(|mv| *mv))
-        });
+        }
+        route.pop();
+        (best_score, best_pv)
+    }
 
-        println!("探索開始...");
-        loop {
-            thread::sleep(Duration::from_millis(200));
+    //ルート局面をself.algorithmで探索する
+    fn root_search(
+        &mut self,
+        board: &mut Bitboard,
+        depth: usize,
+        max_depth: usize,
+        prev_score: Option<i16>,
+        prev_hash: Option<u64>,
+    ) -> (i16, Vec<MoveBit>) {
+        match self.algorithm {
+            SearchAlgorithm::Pvs => {
+                self.aspiration_search(board, depth, max_depth, prev_score, prev_hash)
+            }
+            SearchAlgorithm::MtdF => {
+                self.mtdf_search(board, depth, max_depth, prev_score.unwrap_or(0), prev_hash)
+            }
+            SearchAlgorithm::Negamax => {
+                let mut route = Vec::new();
+                self.negamax(board, depth, &mut route, Node::root(prev_hash))
+            }
+        }
+    }
+
+    //反復深化。ヘルパースレッドは深さをずらしながら同じ置換表を埋める
+    fn iterative_deepening(
+        &mut self,
+        board: &mut Bitboard,
+        max_depth: usize,
+        prev_hash: Option<u64>,
+        helper_idx: Option<usize>,
+    ) -> Option<CompletedSearch> {
+        let mut completed: Option<CompletedSearch> = None;
+        //最低でも1手は返せるよう、手が見つかるまでは時間切れ・ノード数の上限にしない
+        let deadline = self.deadline.take();
+        let max_nodes = self.max_nodes.take();
+
+        for depth_run in 0..=max_depth {
+            if helper_idx
+                .is_some_and(|idx| depth_run < max_depth && is_skipped_depth(idx, depth_run))
             {
-                let info = info_clone_for_ui.lock().unwrap();
-                print!(
-                    "\rDepth: {:2}, Score: {:6}, WinRate: {:.3}, Nodes: {:8}, PV: {:<50}",
-                    info.depth,
-                    info.score,
-                    sigmoid(info.score as f32 / EVAL_VALUE_MALTIPLIER),
-                    info.nodes,
-                    info.pv
-                        .iter()
-                        .map(|m| m.to_string())
-                        .collect::<Vec<_>>()
-                        .join(" ")
-                );
+                continue;
+            }
+            self.trace(|t| t.begin_iteration(depth_run));
+            //MultiPV: 見つかった手を除外しながらルートを繰り返し探索する
+            let mut lines: Vec<SearchLine> = Vec::new();
+            self.excluded_root_moves.clear();
+            for pv_idx in 0..self.multi_pv {
+                self.pv_idx = pv_idx;
+                let prev_score = completed
+                    .as_ref()
+                    .and_then(|c| c.lines.get(pv_idx))
+                    .map(|line| line.score);
+
+                //ルートノードで探索
+                let (score, pv_sequence) =
+                    self.root_search(board, depth_run, max_depth, prev_score, prev_hash);
+                if self.stopped() {
+                    break;
+                }
+                //除外できる手が残っていない
+                if pv_sequence.is_empty() && pv_idx > 0 {
+                    break;
+                }
+                let first_move = pv_sequence.first().copied();
+                lines.push(SearchLine {
+                    depth: depth_run,
+                    score,
+                    pv: pv_sequence,
+                });
+                match first_move {
+                    Some(mv) => self.excluded_root_moves.push(mv),
+                    None => break,
+                }
+            }
+            self.excluded_root_moves.clear();
+            if self.stopped() {
+                break;
+            }
+            lines.sort_by_key(|line| -(line.score as i32));
 
-                // 標準出力をフラッシュして即時表示
-                use std::io::Write;
-                std::io::stdout().flush().unwrap();
+            //手が得られなかった深さでは前の深さの手を残す
+            if lines[0].pv.is_empty()
+                && let Some(prev) = &completed
+            {
+                lines[0].pv = prev.lines[0].pv.clone();
+            }
+            let score = lines[0].score;
+
+            self.report_iteration(depth_run, &lines);
+
+            if !lines[0].pv.is_empty() {
+                self.deadline = deadline;
+                self.max_nodes = max_nodes;
             }
+            completed = Some(CompletedSearch {
+                depth: depth_run,
+                lines,
+            });
 
-            if search_thread.is_finished() {
+            if self.params.is_mate_score(score) {
+                //詰み発見
                 break;
             }
         }
-        search_thread.join().unwrap()
-    })
+        self.flush_nodes();
+        completed
+    }
 }
 
+//ルートで上位options.multi_pv手の読み筋を求める。result.linesは良い順
+//historyは対局でルート局面より前に現れた局面(古い順、最後が直前の局面)
 pub fn find_best_move<F>(
     board: &mut Bitboard,
-    depth: usize,
-    tt: Arc<Mutex<LruCache<u64, TTEntry>>>,
-    prev_hash: Option<u64>,
+    options: &SearchOptions,
+    tt: &TranspositionTable,
+    history: &[u64],
     evaluate: &F,
-) -> (i16, Option<MoveBit>)
+    observer: &dyn SearchObserver,
+) -> SearchResult
 where
     F: Fn(&BoardSnapshot) -> i16 + Sync,
 {
-    let shared_info = Arc::new(Mutex::new(SearchInfo::default()));
-    let info_clone_for_ui = shared_info.clone();
-
-    let tt_for_thread = Arc::clone(&tt);
-    let mut vidro_for_search = board.clone();
-
-    std::thread::scope(|s| {
-        let builder = std::thread::Builder::new()
-            .name("search_thread".into())
-            .stack_size(32 * 1024 * 1024);
-        let search_thread = builder
-            .spawn_scoped(s, move || {
-                let mut tt_guard = tt_for_thread.lock().unwrap();
+    let prev_hash = history.last().copied();
+    let shared = SharedSearchState::default();
+    let start = Instant::now();
+    let deterministic = options.deterministic;
+    let deadline = options
+        .time_limit
+        .filter(|_| !deterministic)
+        .map(|limit| start + limit);
+    let skill = options.skill;
+    let depth = options.effective_depth();
+    let threads = if deterministic { 1 } else { options.threads };
+    //再現可能な設定では局面ごとに決まる乱数で手を選ぶ
+    let mut rng = if deterministic {
+        StdRng::seed_from_u64(board.to_compression_bod())
+    } else {
+        StdRng::from_rng(&mut rand::rng())
+    };
+    let noise_seed: u64 = rng.random();
 
-                let mut best_move_overall: Option<MoveBit> = None;
-                let mut result_score = 0;
+    //historyの長さを対局の手数とみなす
+    if let Some(book) = options.book.as_ref().filter(|b| history.len() < b.depth) {
+        let randomness = if deterministic { 0.0 } else { book.randomness };
+        let choice = book
+            .book
+            .choose(board, prev_hash, randomness, &mut rand::rng());
+        if let Some(choice) = choice {
+            observer.on_start();
+            let score = choice.score.unwrap_or(0);
+            let pv = vec![choice.mv];
+            let result = SearchResult {
+                best_move: Some(choice.mv),
+                score,
+                depth: 0,
+                pv: pv.clone(),
+                lines: vec![SearchLine {
+                    depth: 0,
+                    score,
+                    pv,
+                }],
+                nodes: 0,
+                elapsed: start.elapsed(),
+                from_book: true,
+            };
+            observer.on_finished(&result);
+            return result;
+        }
+    }
 
-                //反復深化ループ
-                for depth_run in 0..=depth {
-                    let mut route = Vec::new();
+    //ノイズを加えた評価値は探索ごとに違うので、前の探索の結果を置換表に残さない
+    if deterministic || skill.is_some_and(|s| s.eval_noise > 0) {
+        tt.clear();
+    }
 
-                    //ルートノードで探索
-                    let (score, pv_sequence) = alphabeta(
+    observer.on_start();
+    let best = thread::scope(|s| {
+        let spawn_search = |name: String, helper_idx: Option<usize>| {
+            let mut vidro_for_search = board.clone();
+            let shared = &shared;
+            thread::Builder::new()
+                .name(name)
+                .stack_size(32 * 1024 * 1024)
+                .spawn_scoped(s, move || {
+                    let mut worker = SearchWorker::new(
+                        tt,
+                        evaluate,
+                        shared,
+                        observer,
+                        helper_idx.is_none(),
+                        deadline,
+                    );
+                    worker.multi_pv = options.multi_pv.max(skill.map_or(1, |s| s.candidates));
+                    worker.aspiration_window = options.aspiration_window;
+                    worker.quiescence_depth = options.quiescence_depth;
+                    worker.algorithm = options.algorithm;
+                    worker.params = options.params.clone();
+                    worker.game_history = history;
+                    worker.stop_signal = options.stop_signal.as_deref().filter(|_| !deterministic);
+                    worker.trace = options.trace.as_deref().filter(|_| helper_idx.is_none());
+                    worker.root_turn = vidro_for_search.turn;
+                    worker.contempt = options.contempt;
+                    worker.moves_left = options.moves_left;
+                    worker.max_nodes = skill.and_then(|s| s.nodes);
+                    worker.skill = skill;
+                    worker.noise_seed = noise_seed;
+                    let completed = worker.iterative_deepening(
                         &mut vidro_for_search,
-                        depth_run,
-                        i16::MIN + depth as i16,
-                        i16::MAX - depth as i16,
-                        &mut tt_guard,
-                        &mut route,
-                        // &mut process,
-                        true,
-                        shared_info.clone(),
+                        depth,
                         prev_hash,
-                        evaluate,
-                        0,
+                        helper_idx,
                     );
-                    result_score = score;
-
-                    //結果をUIに通知
-                    {
-                        let mut info = shared_info.lock().unwrap();
-                        info.score = score;
-                        info.depth = depth_run;
-                        info.pv = pv_sequence.clone();
+                    //メインスレッドが終わったらヘルパーも止める
+                    if helper_idx.is_none() {
+                        shared.stop.store(true, Ordering::Relaxed);
                     }
+                    completed
+                })
+                .expect("faild start-up search_thread")
+        };
 
-                    if !pv_sequence.is_empty() {
-                        best_move_overall = Some(pv_sequence[0].clone());
-                    }
+        let search_thread = spawn_search("search_thread".into(), None);
+        let helper_threads: Vec<_> = (0..threads.saturating_sub(1))
+            .map(|idx| spawn_search(format!("helper_thread_{}", idx), Some(idx)))
+            .collect();
 
-                    if score.abs() >= 29000 {
-                        //詰み発見
-                        break;
-                    }
-                }
+        //探索スレッドの終了を待って最善手を取得
+        let main_result = search_thread.join().unwrap();
+        let helper_results = helper_threads.into_iter().map(|h| h.join().unwrap());
 
-                //最終的な最善手を返す
-                (result_score, best_move_overall)
+        //最も深く読み切ったスレッドの結果を採用する(同じ深さならメインスレッド優先)
+        helper_results
+            .flatten()
+            .fold(main_result, |best, result| match best {
+                Some(b) if b.depth >= result.depth => Some(b),
+                _ => Some(result),
             })
-            .expect("faild start-up search_thread");
+    });
 
-        println!("探索開始...");
-        loop {
-            thread::sleep(Duration::from_millis(200));
-            {
-                let info = info_clone_for_ui.lock().unwrap();
-                print!(
-                    "\rDepth: {:2}, Score: {:6}, WinRate: {:.3}, Nodes: {:8}, PV: {:<50}",
-                    info.depth,
-                    info.score,
-                    sigmoid(info.score as f32 / EVAL_VALUE_MALTIPLIER),
-                    info.nodes,
-                    info.pv
-                        .iter()
-                        .map(|m| m.to_string())
-                        .collect::<Vec<_>>()
-                        .join(" ")
-                );
+    let (depth, lines) = match best {
+        Some(completed) => (completed.depth, completed.lines),
+        None => (0, Vec::new()),
+    };
+    //棋力を落とすときは最善とは限らない読み筋を選ぶ
+    let chosen = match skill {
+        Some(skill) => skill.choose_line(&lines, &mut rng).unwrap_or(0),
+        None => 0,
+    };
+    let (score, pv) = match lines.get(chosen) {
+        Some(line) => (line.score, line.pv.clone()),
+        None => (0, Vec::new()),
+    };
+    let result = SearchResult {
+        best_move: pv.first().copied(),
+        score,
+        depth,
+        pv,
+        lines,
+        nodes: shared.total_nodes.load(Ordering::Relaxed),
+        elapsed: start.elapsed(),
+        from_book: false,
+    };
+    observer.on_finished(&result);
+    result
+}
+
+//テスト用の簡単な評価関数。手番側の石が中央に多いほど良い
+#[cfg(test)]
+pub(crate) fn test_evaluate(snapshot: &BoardSnapshot) -> i16 {
+    use crate::bitboard::BITBOD_WIDTH;
+
+    let relative = snapshot.to_relative();
+    let center =
+        0b01110u64 << BITBOD_WIDTH | 0b01110 << (BITBOD_WIDTH * 2) | 0b01110 << (BITBOD_WIDTH * 3);
+    ((relative.p1 & center).count_ones() as i16 - (relative.p2 & center).count_ones() as i16) * 30
+        + relative.p1.count_ones() as i16
+        - relative.p2.count_ones() as i16
+}
+
+//テストでよく使う序盤の局面
+#[cfg(test)]
+pub(crate) const TEST_MOVES: &str = "S 2 2; S 0 0";
+
+//テスト用。手順の局面を新しい置換表とtest_evaluateで探索する
+#[cfg(test)]
+pub(crate) fn search_moves(moves: &str, options: &SearchOptions) -> SearchResult {
+    use crate::bitboard_console::board_from_moves;
+    use crate::search_observer::SilentObserver;
+
+    let (mut board, history) = board_from_moves(moves).unwrap();
+    let tt = TranspositionTable::new(1);
+    find_best_move(
+        &mut board,
+        options,
+        &tt,
+        &history,
+        &test_evaluate,
+        &SilentObserver,
+    )
+}
+
+#[test]
+fn test_algorithms_agree() {
+    for moves in ["", TEST_MOVES, "S 1 1; S 3 3; S 1 3; S 4 1"] {
+        let scores: Vec<i16> = [
+            SearchAlgorithm::Negamax,
+            SearchAlgorithm::Pvs,
+            SearchAlgorithm::MtdF,
+        ]
+        .into_iter()
+        .map(|algorithm| {
+            //枝刈りや延長があると全幅探索と値が変わるので切る
+            let options = SearchOptions {
+                depth: 3,
+                quiescence_depth: 2,
+                algorithm,
+                params: SearchParams {
+                    null_move: false,
+                    futility: false,
+                    razoring: false,
+                    brinkmate_extension: false,
+                    ..Default::default()
+                },
+                ..Default::default()
+            };
+            let result = search_moves(moves, &options);
+            assert!(result.best_move.is_some());
+            assert_eq!(result.pv.first().copied(), result.best_move);
+            result.score
+        })
+        .collect();
+        assert_eq!(scores[0], scores[1], "{}", moves);
+        assert_eq!(scores[0], scores[2], "{}", moves);
+    }
+}
+
+#[test]
+fn test_lazy_smp_matches_single_thread() {
+    use crate::bitboard_console::board_from_moves;
+
+    //枝刈りや浅く読む手があると置換表の共有で値が変わるので切る
+    let params = SearchParams {
+        lmr_min_depth: usize::MAX,
+        null_move: false,
+        futility: false,
+        razoring: false,
+        brinkmate_extension: false,
+        dfpn: false,
+        ..Default::default()
+    };
+    for moves in ["", TEST_MOVES, "S 1 1; S 3 3; S 1 3; S 4 1"] {
+        let (board, _) = board_from_moves(moves).unwrap();
+        let search = |threads: usize| {
+            let options = SearchOptions {
+                depth: 4,
+                threads,
+                params: params.clone(),
+                ..Default::default()
+            };
+            search_moves(moves, &options)
+        };
+        let single = search(1);
+        for _ in 0..3 {
+            let result = search(4);
+            let best_move = result.best_move.unwrap();
+            assert!(board.iter_legal_move().any(|m| m == best_move), "{}", moves);
+            assert_eq!(result.depth, 4, "{}", moves);
+            assert_eq!(result.score, single.score, "{}", moves);
+        }
+    }
+}
+
+#[test]
+fn test_multi_pv_lines() {
+    use crate::bitboard_console::board_from_moves;
+
+    const MULTI_PV: usize = 4;
+    let (board, _) = board_from_moves(TEST_MOVES).unwrap();
+    for algorithm in [SearchAlgorithm::Pvs, SearchAlgorithm::MtdF] {
+        let options = SearchOptions {
+            depth: 3,
+            multi_pv: MULTI_PV,
+            algorithm,
+            deterministic: true,
+            ..Default::default()
+        };
+        let result = search_moves(TEST_MOVES, &options);
+
+        //ルートの手がそれぞれ違う読み筋を良い順に返す
+        assert_eq!(result.lines.len(), MULTI_PV, "{:?}", algorithm);
+        let mut first_moves: Vec<MoveBit> = result.lines.iter().map(|l| l.pv[0]).collect();
+        assert!(
+            first_moves
+                .iter()
+                .all(|&mv| board.iter_legal_move().any(|m| m == mv))
+        );
+        first_moves.sort_by_key(|mv| (mv.idx, mv.angle_idx));
+        first_moves.dedup();
+        assert_eq!(first_moves.len(), MULTI_PV, "{:?}", algorithm);
+        assert!(result.lines.windows(2).all(|w| w[0].score >= w[1].score));
+        assert_eq!(result.lines[0].pv, result.pv);
+        assert_eq!(result.lines[0].score, result.score);
+    }
+}
+
+#[test]
+fn test_pv_reaches_searched_depth() {
+    use crate::bitboard_console::board_from_moves;
+
+    const DEPTH: usize = 5;
+    for algorithm in [SearchAlgorithm::Pvs, SearchAlgorithm::MtdF] {
+        let options = SearchOptions {
+            depth: DEPTH,
+            algorithm,
+            ..Default::default()
+        };
+        let result = search_moves(TEST_MOVES, &options);
+        assert!(result.pv.len() >= DEPTH, "{:?}", algorithm);
 
-                // 標準出力をフラッシュして即時表示
-                use std::io::Write;
-                std::io::stdout().flush().unwrap();
+        //読み筋は合法手だけで千日手にならない
+        let (mut board, mut history) = board_from_moves(TEST_MOVES).unwrap();
+        for mv in result.pv {
+            assert!(board.iter_legal_move().any(|m| m == mv));
+            let hash = board.to_compression_bod();
+            assert!(!history.contains(&hash));
+            board
+                .apply_force_with_check_illegal_move(mv, history.last().copied())
+                .unwrap();
+            history.push(hash);
+        }
+    }
+}
+
+#[test]
+fn test_contempt_scores_move_limit_draw() {
+    //次の1手で手数制限になるので、1手で勝てなければどの手も引き分け
+    for algorithm in [SearchAlgorithm::Negamax, SearchAlgorithm::Pvs] {
+        for contempt in [50, -50] {
+            let options = SearchOptions {
+                depth: 3,
+                algorithm,
+                contempt,
+                moves_left: Some(1),
+                ..Default::default()
+            };
+            let result = search_moves(TEST_MOVES, &options);
+            assert!(result.best_move.is_some());
+            assert_eq!(result.score, -contempt, "{:?}", algorithm);
+        }
+    }
+}
+
+#[test]
+fn test_game_history_repetition_is_draw() {
+    use crate::bitboard_console::board_from_moves;
+    use crate::search_observer::SilentObserver;
+
+    //どの手を指しても対局中に現れた局面に戻るので、探索経路になくても引き分け
+    for algorithm in [SearchAlgorithm::Negamax, SearchAlgorithm::Pvs] {
+        for contempt in [0, 50, -50] {
+            let (mut board, mut history) = board_from_moves(TEST_MOVES).unwrap();
+            let prev_hash = history.pop();
+            let moves: Vec<MoveBit> = board.iter_legal_move().collect();
+            for mv in moves {
+                board
+                    .apply_force_with_check_illegal_move(mv, prev_hash)
+                    .unwrap();
+                history.push(board.to_compression_bod());
+                board.undo_force(mv);
             }
+            history.extend(prev_hash);
 
-            if search_thread.is_finished() {
-                break;
+            let options = SearchOptions {
+                depth: 3,
+                algorithm,
+                contempt,
+                ..Default::default()
+            };
+            let tt = TranspositionTable::new(1);
+            let result = find_best_move(
+                &mut board,
+                &options,
+                &tt,
+                &history,
+                &test_evaluate,
+                &SilentObserver,
+            );
+            assert!(result.best_move.is_some());
+            assert_eq!(result.score, -contempt, "{:?}", algorithm);
+        }
+    }
+}
+
+#[test]
+fn test_no_pruning_against_win_in_one() {
+    use crate::bench::BENCH_POSITIONS;
+    use crate::bitboard_console::board_from_moves;
+    use crate::search_trace::SearchTrace;
+
+    //詰めろの局面でも枝刈りすると、相手の1手勝ちを見落としてこの局面の勝ちを読めなくなる
+    const THREAT_POSITION: &str = "S 1 2; S 3 4; F 1 2 7; S 3 1; F 0 3 1; F 3 1 2; S 0 1; F 4 1 5";
+
+    let search = |moves: &str, depth: usize, trace: Option<Arc<Mutex<SearchTrace>>>| {
+        let options = SearchOptions {
+            depth,
+            deterministic: true,
+            trace,
+            params: SearchParams {
+                null_move: true,
+                futility: true,
+                razoring: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        search_moves(moves, &options)
+    };
+    let result = search(THREAT_POSITION, 5, None);
+    assert!(
+        SearchParams::default().is_mate_score(result.score),
+        "{}",
+        result.score
+    );
+
+    //記録した各ノードの局面を再現し、詰めろのノードでは枝刈りもパスや確認の探索もしていないことを確かめる
+    let mut threatened = 0;
+    for moves in BENCH_POSITIONS.iter().chain([&THREAT_POSITION]) {
+        for depth in 1..=5 {
+            let trace = Arc::new(Mutex::new(SearchTrace::new(64, 1_000_000)));
+            search(moves, depth, Some(trace.clone()));
+            let trace = trace.lock().unwrap();
+            assert!(!trace.truncated);
+            let (root, history) = board_from_moves(moves).unwrap();
+            //(局面, 直前の局面, 詰めろか)
+            let mut positions: Vec<(Bitboard, Option<u64>, bool)> = Vec::new();
+            for node in &trace.nodes {
+                let (mut board, prev_hash) = match node.parent {
+                    None => (root.clone(), history.last().copied()),
+                    Some(parent) => {
+                        let (mut board, prev_hash, is_threatened) = positions[parent];
+                        let hash = board.to_compression_bod();
+                        assert!(
+                            !is_threatened
+                                || !matches!(node.edge, TraceEdge::NullMove | TraceEdge::Verify),
+                            "{} {:?}",
+                            moves,
+                            node.edge
+                        );
+                        match node.edge {
+                            TraceEdge::Move(mv) => {
+                                board
+                                    .apply_force_with_check_illegal_move(mv, prev_hash)
+                                    .unwrap();
+                                (board, Some(hash))
+                            }
+                            TraceEdge::NullMove => {
+                                board.turn_change();
+                                (board, Some(hash))
+                            }
+                            _ => (board, prev_hash),
+                        }
+                    }
+                };
+                let is_threatened =
+                    !node.quiescence && !board.game_over() && is_reach(&mut board, prev_hash);
+                if is_threatened {
+                    threatened += 1;
+                    assert!(
+                        !node.events.iter().any(|e| matches!(
+                            e,
+                            TraceEvent::Razoring { .. }
+                                | TraceEvent::NullMove { .. }
+                                | TraceEvent::Futility { .. }
+                                | TraceEvent::FutilityPruned { .. }
+                        )),
+                        "{} {:?}",
+                        moves,
+                        node.events
+                    );
+                }
+                positions.push((board, prev_hash, is_threatened));
             }
         }
+    }
+    assert!(threatened > 0);
+}
 
-        //探索スレッドの終了を待って最善手を取得
-        search_thread.join().unwrap()
-    })
+#[test]
+fn test_extend_pv_truncates_unplayable_moves() {
+    use crate::bench::BRINKMATE_POSITION;
+    use crate::bitboard_console::board_from_moves;
+    use crate::search_observer::SilentObserver;
+
+    let (mut board, history) = board_from_moves(BRINKMATE_POSITION).unwrap();
+    let prev_hash = history.last().copied();
+    let hash = board.to_compression_bod();
+
+    //直前の局面に戻るので指せない応手を探す
+    let (first, back) = board
+        .iter_legal_move()
+        .find_map(|mv| {
+            board
+                .apply_force_with_check_illegal_move(mv, prev_hash)
+                .unwrap();
+            let back = board
+                .iter_legal_move()
+                .find(|&reply| board.check_illegal_move(reply, Some(hash)));
+            board.undo_force(mv);
+            back.map(|back| (mv, back))
+        })
+        .unwrap();
+
+    let tt = TranspositionTable::new(1);
+    let shared = SharedSearchState::default();
+    let worker = SearchWorker::new(&tt, &test_evaluate, &shared, &SilentObserver, true, None);
+    let mut pv = vec![first, back, first];
+    worker.extend_pv_from_tt(&mut board, &mut pv, &[], Node::root(prev_hash), 5);
+    assert_eq!(pv, vec![first]);
+    assert_eq!(board.to_compression_bod(), hash);
+}
+
+#[test]
+fn test_dfpn_mate_with_reused_solver() {
+    use crate::bench::BENCH_POSITIONS;
+
+    let search = |dfpn: bool| {
+        let options = SearchOptions {
+            depth: 5,
+            deterministic: true,
+            params: SearchParams {
+                dfpn,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        search_moves(BENCH_POSITIONS[2], &options)
+    };
+    //1つのsolverを使い回しても、df-pnなしと同じ詰みを読む(ノード数にdf-pnの分が加わる)
+    let with_dfpn = search(true);
+    let without_dfpn = search(false);
+    assert!(SearchParams::default().is_mate_score(with_dfpn.score));
+    assert_eq!(with_dfpn.score, without_dfpn.score);
+    assert_eq!(with_dfpn.best_move, without_dfpn.best_move);
+    assert!(with_dfpn.nodes > without_dfpn.nodes);
+}
+
+#[test]
+fn test_brinkmate_extensions_are_capped_per_path() {
+    use crate::bench::BRINKMATE_POSITION;
+    use crate::bitboard_console::board_from_moves;
+    use crate::search_observer::SilentObserver;
+    use crate::search_trace::SearchTrace;
+
+    //ルートまでにextensions回延長した経路として、残り深さ1から探索する
+    let extends = |extensions: usize| {
+        let (mut board, history) = board_from_moves(BRINKMATE_POSITION).unwrap();
+        let tt = TranspositionTable::new(1);
+        let shared = SharedSearchState::default();
+        let trace = Mutex::new(SearchTrace::new(8, 10_000));
+        trace.lock().unwrap().begin_iteration(1);
+        let mut worker =
+            SearchWorker::new(&tt, &test_evaluate, &shared, &SilentObserver, true, None);
+        worker.trace = Some(&trace);
+        let node = Node {
+            extensions,
+            ..Node::root(history.last().copied())
+        };
+        worker.alphabeta(&mut board, 1, -i16::MAX, i16::MAX, &mut Vec::new(), node);
+        let trace = trace.into_inner().unwrap();
+        trace
+            .nodes
+            .iter()
+            .flat_map(|n| &n.events)
+            .any(|e| matches!(e, TraceEvent::Extension { .. }))
+    };
+    let max_extensions = SearchParams::default().brinkmate_max_extensions;
+    assert!(extends(max_extensions - 1));
+    assert!(!extends(max_extensions));
+
+    //延長した回数は子孫に引き継がれる
+    let node = Node::root(None).extended_child(1, 1).child(2);
+    assert_eq!(node.extended_child(3, 0).extensions, 1);
 }
@@ -0,0 +1,204 @@
+use std::io::Write;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use serde_json::json;
+
+use crate::bitboard::MoveBit;
+use crate::eval::sigmoid;
+use crate::search::{SearchInfo, SearchLine, SearchResult};
+
+//探索の進捗を受け取る。探索スレッドから呼ばれる
+pub trait SearchObserver: Sync {
+    fn on_start(&self) {}
+    //1つの深さの探索が終わった
+    fn on_iteration(&self, info: &SearchInfo);
+    //探索中にルートの最善手(読み筋)が更新された
+    fn on_new_best(&self, info: &SearchInfo);
+    fn on_finished(&self, result: &SearchResult);
+}
+
+//何も出力しない
+pub struct SilentObserver;
+
+impl SearchObserver for SilentObserver {
+    fn on_iteration(&self, _: &SearchInfo) {}
+    fn on_new_best(&self, _: &SearchInfo) {}
+    fn on_finished(&self, _: &SearchResult) {}
+}
+
+//コンソールの同じ行(MultiPVのときは行ごと)を書き換えて進捗を表示する
+pub struct ConsoleObserver {
+    eval_multiplier: f32, //勝率表示用(SearchParams::eval_multiplier)
+    state: Mutex<ConsoleState>,
+}
+
+struct ConsoleState {
+    last_print: Option<Instant>,
+    printed_rows: usize,
+}
+
+//読み筋の更新はこの間隔より頻繁には表示しない
+const CONSOLE_INTERVAL: Duration = Duration::from_millis(200);
+
+impl ConsoleObserver {
+    pub fn new(eval_multiplier: f32) -> Self {
+        Self {
+            eval_multiplier,
+            state: Mutex::new(ConsoleState {
+                last_print: None,
+                printed_rows: 0,
+            }),
+        }
+    }
+
+    fn print(&self, info: &SearchInfo, force: bool) {
+        let mut state = self.state.lock().unwrap();
+        if !force
+            && state
+                .last_print
+                .is_some_and(|last| last.elapsed() < CONSOLE_INTERVAL)
+        {
+            return;
+        }
+        state.last_print = Some(Instant::now());
+        print_progress(info, self.eval_multiplier, &mut state.printed_rows);
+    }
+}
+
+impl SearchObserver for ConsoleObserver {
+    fn on_start(&self) {
+        println!("探索開始...");
+    }
+    fn on_iteration(&self, info: &SearchInfo) {
+        self.print(info, true);
+    }
+    fn on_new_best(&self, info: &SearchInfo) {
+        self.print(info, false);
+    }
+    fn on_finished(&self, _: &SearchResult) {}
+}
+
+fn pv_to_string(pv: &[MoveBit]) -> String {
+    pv.iter()
+        .map(|m| m.to_string())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn format_search_line(
+    depth: usize,
+    score: i16,
+    eval_multiplier: f32,
+    nodes: usize,
+    pv: &[MoveBit],
+) -> String {
+    format!(
+        "Depth: {:2}, Score: {:6}, WinRate: {:.3}, Nodes: {:8}, PV: {:<50}",
+        depth,
+        score,
+        sigmoid(score as f32 / eval_multiplier),
+        nodes,
+        pv_to_string(pv)
+    )
+}
+
+//探索の進捗表示。MultiPVのときは読み筋ごとに1行ずつ書き換える
+fn print_progress(info: &SearchInfo, eval_multiplier: f32, printed_rows: &mut usize) {
+    if info.lines.len() <= 1 {
+        print!(
+            "\r{}",
+            format_search_line(
+                info.depth,
+                info.score,
+                eval_multiplier,
+                info.nodes,
+                &info.pv
+            )
+        );
+        *printed_rows = 1;
+    } else {
+        if *printed_rows > 1 {
+            //前回表示した行の先頭まで戻る
+            print!("\u{001b}[{}A", *printed_rows - 1);
+        }
+        for (i, line) in info.lines.iter().enumerate() {
+            print!(
+                "\r\u{001b}[2K{}. {}",
+                i + 1,
+                format_search_line(
+                    line.depth,
+                    line.score,
+                    eval_multiplier,
+                    info.nodes,
+                    &line.pv
+                )
+            );
+            if i + 1 < info.lines.len() {
+                println!();
+            }
+        }
+        *printed_rows = info.lines.len();
+    }
+
+    // 標準出力をフラッシュして即時表示
+    std::io::stdout().flush().unwrap();
+}
+
+//1イベント1行のJSONで出力する(他のプログラムから読む用)
+pub struct JsonObserver;
+
+fn line_to_json(line: &SearchLine) -> serde_json::Value {
+    json!({
+        "depth": line.depth,
+        "score": line.score,
+        "pv": line.pv.iter().map(|m| m.to_string()).collect::<Vec<_>>(),
+    })
+}
+
+impl JsonObserver {
+    fn emit(&self, value: serde_json::Value) {
+        let mut stdout = std::io::stdout().lock();
+        writeln!(stdout, "{}", value).unwrap();
+        stdout.flush().unwrap();
+    }
+
+    fn info_to_json(&self, event: &str, info: &SearchInfo) -> serde_json::Value {
+        let mut value = json!({
+            "event": event,
+            "depth": info.depth,
+            "score": info.score,
+            "nodes": info.nodes,
+            "pv": info.pv.iter().map(|m| m.to_string()).collect::<Vec<_>>(),
+        });
+        //MultiPVのときだけ各読み筋を出す
+        if !info.lines.is_empty() {
+            value["lines"] = info.lines.iter().map(line_to_json).collect();
+        }
+        value
+    }
+}
+
+impl SearchObserver for JsonObserver {
+    fn on_start(&self) {
+        self.emit(json!({ "event": "start" }));
+    }
+    fn on_iteration(&self, info: &SearchInfo) {
+        self.emit(self.info_to_json("iteration", info));
+    }
+    fn on_new_best(&self, info: &SearchInfo) {
+        self.emit(self.info_to_json("new_best", info));
+    }
+    fn on_finished(&self, result: &SearchResult) {
+        self.emit(json!({
+            "event": "finished",
+            "best_move": result.best_move.map(|m| m.to_string()),
+            "depth": result.depth,
+            "score": result.score,
+            "nodes": result.nodes,
+            "elapsed_ms": result.elapsed.as_millis() as u64,
+            "pv": result.pv.iter().map(|m| m.to_string()).collect::<Vec<_>>(),
+            "lines": result.lines.iter().map(line_to_json).collect::<Vec<_>>(),
+        }));
+    }
+}
@@ -0,0 +1,163 @@
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+
+use serde::{Deserialize, Serialize};
+
+//探索の調整用パラメータ。JSONの設定ファイルから読み込める(省略した項目は既定値)
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SearchParams {
+    pub use_cache: bool,                  //置換表を使うか
+    pub win_lose_score: i16,              //勝ち局面の評価値(ここから手数を引く)
+    pub eval_clamp: i16,                  //評価関数の値の上限(詰みの評価値と区別するため)
+    pub eval_multiplier: f32,             //モデルの出力を評価値に変換する倍率
+    pub static_eval_sorting_depth: usize, //この残り深さ以上では評価関数で手を並べ替える
+    pub lmr_min_depth: usize,             //LMRを行う最小の残り深さ
+    pub lmr_move_thresholds: [usize; 3],  //この手目以降をそれぞれ1, 2, 3手浅く読む
+    pub null_move: bool,
+    pub null_move_min_depth: usize,    //null moveを試す最小の残り深さ
+    pub null_move_reduction: usize,    //パスした後に何手浅く読むか
+    pub null_move_verify_depth: usize, //この残り深さ以上ではパスなしの探索で打ち切りを確かめる
+    pub futility: bool,
+    pub futility_max_depth: usize,
+    pub futility_margin: i16, //残り深さ1あたりのマージン
+    pub razoring: bool,
+    pub razoring_max_depth: usize,
+    pub razoring_margin: i16,            //残り深さ1あたりのマージン
+    pub brinkmate_extension: bool,       //残り深さ1で必至をかける手を1手延長する
+    pub brinkmate_max_extensions: usize, //1つの経路で延長する回数の上限
+    pub brinkmate_eval: bool,            //静止探索の末端で必至を負けと評価する
+    pub dfpn: bool,
+    pub dfpn_min_depth: usize, //この残り深さ以上のノードでdf-pnの詰み探索を行う
+    pub dfpn_nodes: usize,     //1回の詰み探索のノード数の上限
+    pub dfpn_max_depth: usize, //何手以内の詰みを探すか
+}
+
+impl Default for SearchParams {
+    fn default() -> Self {
+        Self {
+            use_cache: true,
+            win_lose_score: 30000,
+            eval_clamp: 29000,
+            eval_multiplier: 100.0,
+            static_eval_sorting_depth: 2,
+            lmr_min_depth: 3,
+            lmr_move_thresholds: [4, 10, 25],
+            null_move: false,
+            null_move_min_depth: 3,
+            null_move_reduction: 2,
+            null_move_verify_depth: 6,
+            futility: false,
+            futility_max_depth: 2,
+            futility_margin: 150,
+            razoring: false,
+            razoring_max_depth: 2,
+            razoring_margin: 300,
+            brinkmate_extension: true,
+            brinkmate_max_extensions: 2,
+            brinkmate_eval: true,
+            dfpn: false,
+            dfpn_min_depth: 4,
+            dfpn_nodes: 2000,
+            dfpn_max_depth: 9,
+        }
+    }
+}
+
+//手数分を引いてもこれより大きければ詰みの評価値とみなす
+const MATE_PLY_MARGIN: i16 = 1000;
+
+impl SearchParams {
+    pub fn load(file_path: &str) -> std::io::Result<Self> {
+        let file = File::open(file_path)?;
+        let params: Self =
+            serde_json::from_reader(BufReader::new(file)).map_err(std::io::Error::other)?;
+        params.validate().map_err(std::io::Error::other)?;
+        Ok(params)
+    }
+
+    pub fn save(&self, file_path: &str) -> std::io::Result<()> {
+        let file = File::create(file_path)?;
+        serde_json::to_writer_pretty(BufWriter::new(file), self).map_err(std::io::Error::other)
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.eval_clamp > self.min_mate_score() {
+            return Err(format!(
+                "eval_clamp({})はwin_lose_score - {}以下にしてください",
+                self.eval_clamp, MATE_PLY_MARGIN
+            ));
+        }
+        if self.eval_multiplier <= 0.0 {
+            return Err("eval_multiplierは正の値にしてください".to_string());
+        }
+        if !self.lmr_move_thresholds.is_sorted() {
+            return Err("lmr_move_thresholdsは昇順にしてください".to_string());
+        }
+        Ok(())
+    }
+
+    pub fn uses_pruning(&self) -> bool {
+        self.null_move || self.futility || self.razoring
+    }
+
+    pub fn min_mate_score(&self) -> i16 {
+        self.win_lose_score - MATE_PLY_MARGIN
+    }
+
+    pub fn is_mate_score(&self, score: i16) -> bool {
+        let min = self.min_mate_score();
+        !(-min..=min).contains(&score)
+    }
+
+    //詰みの評価値はルートからの手数で決まるので、置換表にはその局面からの手数で保存する
+    pub fn score_to_tt(&self, score: i16, ply: usize) -> i16 {
+        let min = self.min_mate_score();
+        if score > min {
+            score + ply as i16
+        } else if score < -min {
+            score - ply as i16
+        } else {
+            score
+        }
+    }
+
+    pub fn score_from_tt(&self, score: i16, ply: usize) -> i16 {
+        let min = self.min_mate_score();
+        if score > min {
+            score - ply as i16
+        } else if score < -min {
+            score + ply as i16
+        } else {
+            score
+        }
+    }
+
+    //LMRで何手浅く読むか(i: 並べ替え後の手の順番)
+    pub fn lmr_reduction(&self, i: usize) -> usize {
+        self.lmr_move_thresholds.iter().filter(|&&t| i >= t).count()
+    }
+
+    //モデルの出力(勝率のロジット)を評価値に変換する
+    pub fn eval_from_logit(&self, z: f32) -> i16 {
+        ((z * self.eval_multiplier) as i16).clamp(-self.eval_clamp, self.eval_clamp)
+    }
+}
+
+#[test]
+fn test_partial_config() {
+    //書かれていない項目は既定値になる
+    let params: SearchParams =
+        serde_json::from_str(r#"{ "lmr_move_thresholds": [3, 8, 20] }"#).unwrap();
+    assert_eq!(params.lmr_move_thresholds, [3, 8, 20]);
+    assert_eq!(
+        params.win_lose_score,
+        SearchParams::default().win_lose_score
+    );
+    assert!(params.validate().is_ok());
+
+    assert_eq!(params.lmr_reduction(2), 0);
+    assert_eq!(params.lmr_reduction(3), 1);
+    assert_eq!(params.lmr_reduction(8), 2);
+    assert_eq!(params.lmr_reduction(30), 3);
+}
@@ -0,0 +1,396 @@
+use serde_json::{Value, json};
+
+use crate::bitboard::MoveBit;
+use crate::transposition_table::TTFlag;
+
+//ノードで起きたこと
+#[derive(Clone, Debug)]
+pub enum TraceEvent {
+    //置換表に深さが足りるエントリがあった。cutoffならその値で打ち切った
+    TTHit {
+        flag: TTFlag,
+        depth: u8,
+        score: i16,
+        cutoff: bool,
+    },
+    Repetition,
+    DfpnMate {
+        len: usize,
+    },
+    Razoring {
+        static_eval: i16,
+    },
+    NullMove {
+        score: i16,
+    },
+    Futility {
+        value: i16,
+    },
+    FutilityPruned {
+        mv: MoveBit,
+    },
+    //必至をかける手の延長
+    Extension {
+        mv: MoveBit,
+    },
+    BetaCutoff {
+        mv: MoveBit,
+        index: usize,
+    },
+    StandPat {
+        score: i16,
+    },
+    MateInOne {
+        mv: MoveBit,
+    },
+    Brinkmate,
+}
+
+impl TraceEvent {
+    fn to_json(&self) -> Value {
+        match self {
+            TraceEvent::TTHit {
+                flag,
+                depth,
+                score,
+                cutoff,
+            } => json!({
+                "type": "tt_hit",
+                "flag": format!("{:?}", flag),
+                "depth": depth,
+                "score": score,
+                "cutoff": cutoff,
+            }),
+            TraceEvent::Repetition => json!({ "type": "repetition" }),
+            TraceEvent::DfpnMate { len } => json!({ "type": "dfpn_mate", "len": len }),
+            TraceEvent::Razoring { static_eval } => {
+                json!({ "type": "razoring", "static_eval": static_eval })
+            }
+            TraceEvent::NullMove { score } => json!({ "type": "null_move", "score": score }),
+            TraceEvent::Futility { value } => json!({ "type": "futility", "value": value }),
+            TraceEvent::FutilityPruned { mv } => {
+                json!({ "type": "futility_pruned", "move": mv.to_string() })
+            }
+            TraceEvent::Extension { mv } => json!({ "type": "extension", "move": mv.to_string() }),
+            TraceEvent::BetaCutoff { mv, index } => {
+                json!({ "type": "beta_cutoff", "move": mv.to_string(), "index": index })
+            }
+            TraceEvent::StandPat { score } => json!({ "type": "stand_pat", "score": score }),
+            TraceEvent::MateInOne { mv } => {
+                json!({ "type": "mate_in_one", "move": mv.to_string() })
+            }
+            TraceEvent::Brinkmate => json!({ "type": "brinkmate" }),
+        }
+    }
+
+    //DOTのラベル用の短い表記
+    fn label(&self) -> String {
+        match self {
+            TraceEvent::TTHit {
+                flag,
+                depth,
+                score,
+                cutoff,
+            } => format!(
+                "TT {:?} d={} {}{}",
+                flag,
+                depth,
+                score,
+                if *cutoff { " cut" } else { "" }
+            ),
+            TraceEvent::Repetition => "千日手".to_string(),
+            TraceEvent::DfpnMate { len } => format!("df-pn {}手詰め", len),
+            TraceEvent::Razoring { static_eval } => format!("razoring {}", static_eval),
+            TraceEvent::NullMove { score } => format!("null move {}", score),
+            TraceEvent::Futility { value } => format!("futility {}", value),
+            TraceEvent::FutilityPruned { mv } => format!("futility枝刈り {}", mv.to_string()),
+            TraceEvent::Extension { mv } => format!("延長 {}", mv.to_string()),
+            TraceEvent::BetaCutoff { mv, index } => {
+                format!("beta cut {} (#{})", mv.to_string(), index)
+            }
+            TraceEvent::StandPat { score } => format!("stand pat {}", score),
+            TraceEvent::MateInOne { mv } => format!("1手勝ち {}", mv.to_string()),
+            TraceEvent::Brinkmate => "必至".to_string(),
+        }
+    }
+}
+
+//親ノードからどうやって来たか
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum TraceEdge {
+    #[default]
+    Root,
+    Move(MoveBit),
+    NullMove,
+    //同じ局面での確認の探索(null moveの検証探索、razoringの静止探索)
+    Verify,
+}
+
+impl TraceEdge {
+    fn label(&self) -> String {
+        match self {
+            TraceEdge::Root => "root".to_string(),
+            TraceEdge::Move(mv) => mv.to_string(),
+            TraceEdge::NullMove => "null".to_string(),
+            TraceEdge::Verify => "verify".to_string(),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct TraceNode {
+    pub parent: Option<usize>,
+    pub edge: TraceEdge,
+    pub quiescence: bool,
+    pub ply: usize,
+    pub depth: usize,
+    pub reduction: usize, //LMRで減らした深さ
+    pub alpha: i16,
+    pub beta: i16,
+    pub score: Option<i16>, //停止で結果が出なかったときはNone
+    pub best_move: Option<MoveBit>,
+    pub events: Vec<TraceEvent>,
+    pub hidden_children: usize, //記録する手数や個数の上限を超えて省いた子ノード
+}
+
+//探索木の記録。最後に始めた反復深化の深さの木だけを残す
+#[derive(Debug, Default)]
+pub struct SearchTrace {
+    pub max_ply: usize,
+    pub max_nodes: usize,
+    pub depth: usize, //記録している反復深化の深さ
+    pub nodes: Vec<TraceNode>,
+    pub truncated: bool, //max_nodesに達した
+    stack: Vec<Option<usize>>,
+    pending_edge: TraceEdge,
+    pending_reduction: usize,
+}
+
+impl SearchTrace {
+    pub fn new(max_ply: usize, max_nodes: usize) -> Self {
+        Self {
+            max_ply,
+            max_nodes,
+            ..Default::default()
+        }
+    }
+
+    pub fn begin_iteration(&mut self, depth: usize) {
+        self.depth = depth;
+        self.nodes.clear();
+        self.truncated = false;
+        self.stack.clear();
+    }
+
+    //次に入るノードへの辺(reductionはLMRで減らした深さ)
+    pub fn set_next_edge(&mut self, edge: TraceEdge, reduction: usize) {
+        self.pending_edge = edge;
+        self.pending_reduction = reduction;
+    }
+
+    pub fn enter(&mut self, ply: usize, depth: usize, alpha: i16, beta: i16, quiescence: bool) {
+        let edge = std::mem::take(&mut self.pending_edge);
+        let reduction = std::mem::take(&mut self.pending_reduction);
+        let parent = self.stack.last().copied().flatten();
+        //記録しない親の子孫や、ルート以外から始まった探索(全幅探索の静止探索など)は記録しない
+        let orphan = if self.stack.is_empty() {
+            ply > 0
+        } else {
+            parent.is_none()
+        };
+        let is_full = self.nodes.len() >= self.max_nodes;
+        if orphan || ply > self.max_ply || is_full {
+            if let Some(parent) = parent {
+                self.nodes[parent].hidden_children += 1;
+            }
+            self.truncated |= is_full && !orphan && ply <= self.max_ply;
+            self.stack.push(None);
+            return;
+        }
+        self.nodes.push(TraceNode {
+            parent,
+            edge: if parent.is_none() {
+                TraceEdge::Root
+            } else {
+                edge
+            },
+            quiescence,
+            ply,
+            depth,
+            reduction,
+            alpha,
+            beta,
+            score: None,
+            best_move: None,
+            events: Vec::new(),
+            hidden_children: 0,
+        });
+        self.stack.push(Some(self.nodes.len() - 1));
+    }
+
+    pub fn event(&mut self, event: TraceEvent) {
+        if let Some(Some(id)) = self.stack.last() {
+            self.nodes[*id].events.push(event);
+        }
+    }
+
+    pub fn exit(&mut self, score: Option<i16>, best_move: Option<MoveBit>) {
+        if let Some(Some(id)) = self.stack.pop() {
+            self.nodes[id].score = score;
+            self.nodes[id].best_move = best_move;
+        }
+    }
+
+    fn children(&self) -> Vec<Vec<usize>> {
+        let mut children = vec![Vec::new(); self.nodes.len()];
+        for (id, node) in self.nodes.iter().enumerate() {
+            if let Some(parent) = node.parent {
+                children[parent].push(id);
+            }
+        }
+        children
+    }
+
+    //ルートごとに入れ子にしたJSON(アスピレーションの再探索などでルートは複数になる)
+    pub fn to_json(&self) -> Value {
+        let children = self.children();
+        fn node_json(trace: &SearchTrace, children: &[Vec<usize>], id: usize) -> Value {
+            let node = &trace.nodes[id];
+            json!({
+                "move": node.edge.label(),
+                "ply": node.ply,
+                "depth": node.depth,
+                "reduction": node.reduction,
+                "quiescence": node.quiescence,
+                "alpha": node.alpha,
+                "beta": node.beta,
+                "score": node.score,
+                "best_move": node.best_move.map(|m| m.to_string()),
+                "events": node.events.iter().map(|e| e.to_json()).collect::<Vec<_>>(),
+                "hidden_children": node.hidden_children,
+                "children": children[id]
+                    .iter()
+                    .map(|&c| node_json(trace, children, c))
+                    .collect::<Vec<_>>(),
+            })
+        }
+        json!({
+            "depth": self.depth,
+            "max_ply": self.max_ply,
+            "nodes": self.nodes.len(),
+            "truncated": self.truncated,
+            "roots": self
+                .nodes
+                .iter()
+                .enumerate()
+                .filter(|(_, node)| node.parent.is_none())
+                .map(|(id, _)| node_json(self, &children, id))
+                .collect::<Vec<_>>(),
+        })
+    }
+
+    //Graphvizで描けるDOT形式。打ち切ったノードは赤、置換表で返したノードは青、静止探索は破線
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::from("digraph search {\n    node [shape=box, fontsize=10];\n");
+        for (id, node) in self.nodes.iter().enumerate() {
+            let mut label = format!(
+                "{}\\nd={}{} [{}, {}]\\nscore={}",
+                node.edge.label(),
+                node.depth,
+                if node.reduction > 0 {
+                    format!(" (-{})", node.reduction)
+                } else {
+                    String::new()
+                },
+                node.alpha,
+                node.beta,
+                node.score.map_or("-".to_string(), |s| s.to_string())
+            );
+            for event in &node.events {
+                label += &format!("\\n{}", event.label());
+            }
+            if node.hidden_children > 0 {
+                label += &format!("\\n(+{}ノード省略)", node.hidden_children);
+            }
+            let mut attrs = Vec::new();
+            if node.quiescence {
+                attrs.push("style=dashed");
+            }
+            if node
+                .events
+                .iter()
+                .any(|e| matches!(e, TraceEvent::TTHit { cutoff: true, .. }))
+            {
+                attrs.push("color=blue");
+            } else if node.events.iter().any(|e| {
+                matches!(
+                    e,
+                    TraceEvent::BetaCutoff { .. }
+                        | TraceEvent::Razoring { .. }
+                        | TraceEvent::NullMove { .. }
+                        | TraceEvent::StandPat { .. }
+                )
+            }) {
+                attrs.push("color=red");
+            }
+            let attrs = attrs.iter().map(|a| format!(", {}", a)).collect::<String>();
+            dot += &format!("    n{} [label=\"{}\"{}];\n", id, label, attrs);
+            if let Some(parent) = node.parent {
+                dot += &format!("    n{} -> n{};\n", parent, id);
+            }
+        }
+        dot += "}\n";
+        dot
+    }
+}
+
+#[test]
+fn test_trace_matches_search() {
+    use std::sync::{Arc, Mutex};
+
+    use crate::search::{SearchOptions, TEST_MOVES, search_moves};
+
+    let search = |trace: Option<Arc<Mutex<SearchTrace>>>| {
+        let options = SearchOptions {
+            depth: 4,
+            deterministic: true,
+            trace,
+            ..Default::default()
+        };
+        search_moves(TEST_MOVES, &options)
+    };
+    let trace = Arc::new(Mutex::new(SearchTrace::new(2, 10_000)));
+    let traced = search(Some(trace.clone()));
+    let untraced = search(None);
+    //記録しても探索は変わらない
+    assert_eq!(traced.pv, untraced.pv);
+    assert_eq!(traced.nodes, untraced.nodes);
+
+    let trace = trace.lock().unwrap();
+    assert_eq!(trace.depth, 4);
+    assert!(!trace.truncated);
+    let root = trace
+        .nodes
+        .iter()
+        .rev()
+        .find(|n| n.parent.is_none())
+        .unwrap();
+    assert_eq!(root.score, Some(traced.score));
+    assert_eq!(root.best_move, traced.best_move);
+    for node in &trace.nodes {
+        assert!(node.ply <= 2);
+        if let Some(parent) = node.parent {
+            let expected = match node.edge {
+                TraceEdge::Verify => trace.nodes[parent].ply,
+                _ => trace.nodes[parent].ply + 1,
+            };
+            assert_eq!(node.ply, expected);
+        }
+    }
+    let dot = trace.to_dot();
+    assert!(dot.starts_with("digraph"));
+    assert_eq!(
+        dot.matches(" -> ").count(),
+        trace.nodes.len() - trace.to_json()["roots"].as_array().unwrap().len()
+    );
+}
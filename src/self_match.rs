@@ -3,26 +3,32 @@ use std::collections::HashSet;
 use crate::{
     bitboard::{Bitboard, MoveBit, MoveList},
     eval::{AiModel, GameResult},
-    random_state_generator::random_state_generator,
+    random_state_generator::random_state_generator_with_rng,
     snapshot::BoardSnapshot,
     snapshot_features::BoardSnapshotFeatures,
 };
 use rand::{
-    Rng,
+    Rng, SeedableRng,
     distr::{Distribution, weighted::WeightedIndex},
+    rngs::StdRng,
 };
 use rayon::prelude::*;
 
+//seedを指定すると対局ごとにseed + 対局番号で乱数を初期化し、同じデータを生成する
 pub fn generate_self_play_data(
     random_moves_until: usize,
     current_model: &AiModel,
     past_models: &[AiModel],
     batch_size: usize,
+    seed: Option<u64>,
 ) -> Vec<GameResult> {
     (0..batch_size)
         .into_par_iter()
-        .map(|_| {
-            let mut rng = rand::rng();
+        .map(|i| {
+            let mut rng = match seed {
+                Some(seed) => StdRng::seed_from_u64(seed.wrapping_add(i as u64)),
+                None => StdRng::from_rng(&mut rand::rng()),
+            };
 
             let p2_model = if !past_models.is_empty() && rng.random_bool(0.3) {
                 &past_models[rng.random_range(0..past_models.len())]
@@ -30,7 +36,8 @@ pub fn generate_self_play_data(
                 current_model
             };
 
-            let (mut board, mut prev_hash) = random_state_generator(random_moves_until);
+            let (mut board, mut prev_hash) =
+                random_state_generator_with_rng(random_moves_until, &mut rng);
             let mut history: Vec<BoardSnapshot> = Vec::with_capacity(20);
 
             let mut seen_state: HashSet<u64> = HashSet::new();
@@ -57,7 +64,9 @@ pub fn generate_self_play_data(
                     p2_model
                 };
 
-                if let Some(mv) = select_move_softmax(&board, model_to_use, temp, prev_hash) {
+                if let Some(mv) =
+                    select_move_softmax(&board, model_to_use, temp, prev_hash, &mut rng)
+                {
                     if board
                         .apply_force_with_check_illegal_move(mv, Some(current_hash))
                         .is_err()
@@ -88,11 +97,12 @@ pub fn generate_self_play_data(
         .collect()
 }
 
-fn select_move_softmax(
+pub fn select_move_softmax(
     board: &Bitboard,
     ai_model: &AiModel,
     temperature: f32,
     prev_hash: Option<u64>,
+    rng: &mut impl Rng,
 ) -> Option<MoveBit> {
     let hash = board.to_compression_bod();
     let mut legal_moves = MoveList::new();
@@ -128,9 +138,8 @@ fn select_move_softmax(
         .collect();
 
     let dist = WeightedIndex::new(&weights_prob).unwrap();
-    let mut rng = rand::rng();
 
-    Some(legal_moves[dist.sample(&mut rng)])
+    Some(legal_moves[dist.sample(rng)])
 }
 
 const MAX_SCORE_ABS: f32 = 30000.0;
@@ -0,0 +1,205 @@
+use rand::Rng;
+use rand::distr::{Distribution, weighted::WeightedIndex};
+
+use crate::search::SearchLine;
+use crate::util::splitmix64;
+
+//棋力を落とした探索の設定。弱くする手段を段階的に組み合わせる
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Skill {
+    pub level: usize,
+    pub depth: usize,         //探索深さの上限
+    pub nodes: Option<usize>, //1手あたりのノード数の上限
+    pub candidates: usize,    //ルートで評価値を求める手の数(MultiPV)
+    pub eval_noise: i16,      //末端の評価値に加えるノイズの幅(±)
+    pub margin: i16,          //最善手との評価値の差がこれ以内の手から選ぶ
+    pub blunder_rate: f32,    //候補の手すべてから無作為に選ぶ確率
+    pub elo: i32,             //制限なしの深さ5の探索に対するレーティング差の目安
+}
+
+//レベル0が最も弱い。eloは既定の設定のcalibrateコマンド(80局ずつ)で測った値を丸めたもの
+pub const SKILL_LEVELS: [Skill; 10] = [
+    Skill {
+        level: 0,
+        depth: 1,
+        nodes: None,
+        candidates: 12,
+        eval_noise: 500,
+        margin: 600,
+        blunder_rate: 0.60,
+        elo: -470,
+    },
+    Skill {
+        level: 1,
+        depth: 1,
+        nodes: None,
+        candidates: 10,
+        eval_noise: 300,
+        margin: 400,
+        blunder_rate: 0.35,
+        elo: -380,
+    },
+    Skill {
+        level: 2,
+        depth: 1,
+        nodes: None,
+        candidates: 8,
+        eval_noise: 200,
+        margin: 300,
+        blunder_rate: 0.25,
+        elo: -340,
+    },
+    Skill {
+        level: 3,
+        depth: 2,
+        nodes: None,
+        candidates: 8,
+        eval_noise: 150,
+        margin: 200,
+        blunder_rate: 0.15,
+        elo: -160,
+    },
+    Skill {
+        level: 4,
+        depth: 2,
+        nodes: None,
+        candidates: 6,
+        eval_noise: 120,
+        margin: 150,
+        blunder_rate: 0.10,
+        elo: -130,
+    },
+    Skill {
+        level: 5,
+        depth: 3,
+        nodes: None,
+        candidates: 6,
+        eval_noise: 90,
+        margin: 100,
+        blunder_rate: 0.06,
+        elo: -90,
+    },
+    Skill {
+        level: 6,
+        depth: 3,
+        nodes: Some(30_000),
+        candidates: 6,
+        eval_noise: 60,
+        margin: 60,
+        blunder_rate: 0.04,
+        elo: -80,
+    },
+    Skill {
+        level: 7,
+        depth: 4,
+        nodes: Some(60_000),
+        candidates: 6,
+        eval_noise: 40,
+        margin: 40,
+        blunder_rate: 0.02,
+        elo: -30,
+    },
+    Skill {
+        level: 8,
+        depth: 4,
+        nodes: Some(150_000),
+        candidates: 6,
+        eval_noise: 20,
+        margin: 20,
+        blunder_rate: 0.01,
+        elo: -20,
+    },
+    Skill {
+        level: 9,
+        depth: 5,
+        nodes: Some(300_000),
+        candidates: 6,
+        eval_noise: 10,
+        margin: 10,
+        blunder_rate: 0.0,
+        elo: -10,
+    },
+];
+
+impl Skill {
+    pub fn from_level(level: usize) -> Option<Skill> {
+        SKILL_LEVELS.get(level).copied()
+    }
+
+    //局面ごとに決まるノイズ。同じ探索の中では同じ局面に同じ値を加える
+    pub fn noise(&self, key: u64, seed: u64) -> i16 {
+        if self.eval_noise <= 0 {
+            return 0;
+        }
+        let x = splitmix64(key ^ seed);
+        let range = self.eval_noise as u64 * 2 + 1;
+        (x % range) as i16 - self.eval_noise
+    }
+
+    //読み筋(良い順)から指す手を選ぶ。blunder_rateの確率で候補すべてから、
+    //それ以外は最善手との差がmargin以内の手から差が小さいほど選ばれやすく選ぶ
+    pub fn choose_line(&self, lines: &[SearchLine], rng: &mut impl Rng) -> Option<usize> {
+        let candidates = lines.iter().take_while(|line| !line.pv.is_empty()).count();
+        if candidates == 0 {
+            return None;
+        }
+        if rng.random::<f32>() < self.blunder_rate {
+            return Some(rng.random_range(0..candidates));
+        }
+        let best = lines[0].score as i32;
+        let weights: Vec<i32> = lines[..candidates]
+            .iter()
+            .map(|line| (self.margin as i32 - (best - line.score as i32) + 1).max(0))
+            .collect();
+        let dist = WeightedIndex::new(&weights).ok()?;
+        Some(dist.sample(rng))
+    }
+}
+
+//勝ち点の割合からレーティング差を求める
+pub fn elo_from_score(score: f32) -> f32 {
+    let score = score.clamp(0.001, 0.999);
+    -400.0 * (1.0 / score - 1.0).log10()
+}
+
+#[test]
+fn test_skill_choice_stays_near_best() {
+    use crate::bitboard::MoveBit;
+
+    let line = |score: i16, idx: u8| SearchLine {
+        depth: 3,
+        score,
+        pv: vec![MoveBit::from_idx(idx, 8)],
+    };
+    let lines = vec![line(100, 0), line(90, 1), line(-200, 2), line(-500, 3)];
+    let mut rng = rand::rng();
+
+    //ミスをしなければ差がmargin以内の手だけを選ぶ
+    let skill = Skill {
+        blunder_rate: 0.0,
+        ..SKILL_LEVELS[6]
+    };
+    let mut counts = [0; 4];
+    for _ in 0..1000 {
+        counts[skill.choose_line(&lines, &mut rng).unwrap()] += 1;
+    }
+    assert!(counts[0] > counts[1] && counts[1] > 0);
+    assert_eq!(counts[2] + counts[3], 0);
+
+    //必ずミスをするなら悪い手も選ぶ
+    let skill = Skill {
+        blunder_rate: 1.0,
+        ..SKILL_LEVELS[6]
+    };
+    assert!((0..1000).any(|_| skill.choose_line(&lines, &mut rng) == Some(3)));
+
+    //ノイズは幅の中に収まり、同じ局面とシードなら同じ値
+    let skill = SKILL_LEVELS[0];
+    for key in 0..1000u64 {
+        let noise = skill.noise(key, 7);
+        assert!(noise.abs() <= skill.eval_noise);
+        assert_eq!(noise, skill.noise(key, 7));
+    }
+    assert!((elo_from_score(0.5)).abs() < 1e-3);
+    assert!(SKILL_LEVELS.windows(2).all(|w| w[0].elo < w[1].elo));
+}
@@ -0,0 +1,195 @@
+use rand::Rng;
+use rayon::prelude::*;
+
+use crate::eval::AiModel;
+use crate::game::{self, Game, MAX_MOVES};
+use crate::players::SearchPlayer;
+use crate::random_state_generator::random_state_generator;
+use crate::search::SearchOptions;
+use crate::search_observer::SilentObserver;
+use crate::search_params::SearchParams;
+use crate::transposition_table::TranspositionTable;
+
+//SPSAで調整するパラメータ
+struct Tunable {
+    name: &'static str,
+    min: f64,
+    max: f64,
+    c_end: f64, //最後の反復での摂動の大きさ
+    get: fn(&SearchParams) -> f64,
+    set: fn(&mut SearchParams, f64),
+}
+
+const TUNABLES: [Tunable; 6] = [
+    Tunable {
+        name: "eval_multiplier",
+        min: 30.0,
+        max: 300.0,
+        c_end: 10.0,
+        get: |p| p.eval_multiplier as f64,
+        set: |p, v| p.eval_multiplier = v as f32,
+    },
+    Tunable {
+        name: "static_eval_sorting_depth",
+        min: 1.0,
+        max: 6.0,
+        c_end: 0.5,
+        get: |p| p.static_eval_sorting_depth as f64,
+        set: |p, v| p.static_eval_sorting_depth = v.round() as usize,
+    },
+    Tunable {
+        name: "lmr_min_depth",
+        min: 2.0,
+        max: 6.0,
+        c_end: 0.5,
+        get: |p| p.lmr_min_depth as f64,
+        set: |p, v| p.lmr_min_depth = v.round() as usize,
+    },
+    Tunable {
+        name: "lmr_move_threshold_1",
+        min: 1.0,
+        max: 20.0,
+        c_end: 1.5,
+        get: |p| p.lmr_move_thresholds[0] as f64,
+        set: |p, v| p.lmr_move_thresholds[0] = v.round() as usize,
+    },
+    Tunable {
+        name: "lmr_move_threshold_2",
+        min: 2.0,
+        max: 40.0,
+        c_end: 3.0,
+        get: |p| p.lmr_move_thresholds[1] as f64,
+        set: |p, v| p.lmr_move_thresholds[1] = v.round() as usize,
+    },
+    Tunable {
+        name: "lmr_move_threshold_3",
+        min: 3.0,
+        max: 80.0,
+        c_end: 5.0,
+        get: |p| p.lmr_move_thresholds[2] as f64,
+        set: |p, v| p.lmr_move_thresholds[2] = v.round() as usize,
+    },
+];
+
+//SPSAの係数(Fishtestと同じ形: a_k = a / (A + k + 1)^ALPHA, c_k = c / (k + 1)^GAMMA)
+const ALPHA: f64 = 0.602;
+const GAMMA: f64 = 0.101;
+//最後の反復での学習率(a_end = R_END * c_end^2)
+const R_END: f64 = 0.002;
+
+//対局の設定
+const GAME_HASH_MB: usize = 16;
+
+pub struct SpsaSettings {
+    pub iterations: usize,
+    pub pairs_per_iteration: usize, //1回の更新で行う対局の組数(先後入れ替えで2局ずつ)
+    pub random_opening_moves: usize, //開始局面を作るためのランダムな手数
+    pub output_path: String,        //反復ごとに現在の値を書き出す
+}
+
+fn to_params(base: &SearchParams, theta: &[f64]) -> SearchParams {
+    let mut params = base.clone();
+    for (tunable, &v) in TUNABLES.iter().zip(theta) {
+        (tunable.set)(&mut params, v);
+    }
+    //閾値の大小関係が崩れないようにする
+    params.lmr_move_thresholds.sort();
+    params
+}
+
+//1局指して先手から見た結果を返す(勝ち1.0, 引き分け0.5, 負け0.0)
+fn play_game(
+    mut game: Game,
+    players: [&SearchOptions; 2], //[先手, 後手]
+    model: &AiModel,
+) -> f32 {
+    let evaluators = [
+        model.evaluator(&players[0].params),
+        model.evaluator(&players[1].params),
+    ];
+    let tts = [
+        TranspositionTable::new(GAME_HASH_MB),
+        TranspositionTable::new(GAME_HASH_MB),
+    ];
+    let mut first = SearchPlayer::new(players[0].clone(), &tts[0], &evaluators[0], &SilentObserver);
+    let mut second =
+        SearchPlayer::new(players[1].clone(), &tts[1], &evaluators[1], &SilentObserver);
+    game::play_game(&mut game, [&mut first, &mut second], MAX_MOVES, false).score_for_first()
+}
+
+//options.paramsを初期値として、摂動させたパラメータ同士の対局結果から勾配を推定して調整する
+pub fn spsa_tune(
+    options: &SearchOptions,
+    model: &AiModel,
+    settings: &SpsaSettings,
+) -> SearchParams {
+    let base = &options.params;
+    let n = settings.iterations.max(1) as f64;
+    let big_a = n * 0.1;
+    let mut theta: Vec<f64> = TUNABLES.iter().map(|t| (t.get)(base)).collect();
+
+    for k in 0..settings.iterations {
+        let mut rng = rand::rng();
+        let deltas: Vec<f64> = TUNABLES
+            .iter()
+            .map(|_| if rng.random_bool(0.5) { 1.0 } else { -1.0 })
+            .collect();
+        let c_k: Vec<f64> = TUNABLES
+            .iter()
+            .map(|t| t.c_end * (n / (k as f64 + 1.0)).powf(GAMMA))
+            .collect();
+
+        let perturbed = |sign: f64| -> Vec<f64> {
+            TUNABLES
+                .iter()
+                .enumerate()
+                .map(|(i, t)| (theta[i] + sign * c_k[i] * deltas[i]).clamp(t.min, t.max))
+                .collect()
+        };
+        let plus = SearchOptions {
+            params: to_params(base, &perturbed(1.0)),
+            ..options.clone()
+        };
+        let minus = SearchOptions {
+            params: to_params(base, &perturbed(-1.0)),
+            ..options.clone()
+        };
+
+        //同じ開始局面で先後を入れ替えて2局指す。plus側の(勝ち - 負け)
+        let result: f64 = (0..settings.pairs_per_iteration)
+            .into_par_iter()
+            .map(|_| {
+                let (board, prev_hash) = random_state_generator(settings.random_opening_moves);
+                let game = Game::from_position(board, prev_hash.into_iter().collect());
+                let first = play_game(game.clone(), [&plus, &minus], model);
+                let second = play_game(game, [&minus, &plus], model);
+                ((first - (1.0 - first)) + ((1.0 - second) - second)) as f64
+            })
+            .sum();
+
+        for (i, t) in TUNABLES.iter().enumerate() {
+            let a_k =
+                R_END * t.c_end.powi(2) * ((big_a + n) / (big_a + k as f64 + 1.0)).powf(ALPHA);
+            theta[i] = (theta[i] + a_k * result / (c_k[i] * deltas[i])).clamp(t.min, t.max);
+        }
+
+        let tuned = to_params(base, &theta);
+        println!(
+            "iter {:4}: result {:+.1} / {} games  {}",
+            k + 1,
+            result,
+            settings.pairs_per_iteration * 2,
+            TUNABLES
+                .iter()
+                .zip(&theta)
+                .map(|(t, v)| format!("{}={:.2}", t.name, v))
+                .collect::<Vec<_>>()
+                .join(" ")
+        );
+        if let Err(e) = tuned.save(&settings.output_path) {
+            eprintln!("Err to save: {}", e);
+        }
+    }
+
+    to_params(base, &theta)
+}
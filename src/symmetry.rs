@@ -0,0 +1,138 @@
+use crate::bitboard::{BITBOD_WIDTH, Bitboard, FIELD_BOD_WIDTH, MoveBit};
+
+//弾く方向(angle_idx)ごとの(行, 列)の向き
+const DIRECTIONS: [(i8, i8); 8] = [
+    (0, 1),
+    (1, 1),
+    (1, 0),
+    (1, -1),
+    (0, -1),
+    (-1, -1),
+    (-1, 0),
+    (-1, 1),
+];
+
+const LAST: i8 = FIELD_BOD_WIDTH as i8 - 1;
+
+//盤面の8通りの対称変換(回転と鏡映)。ルールは盤の対称性で変わらない
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Symmetry(u8);
+
+impl Symmetry {
+    pub fn all() -> impl Iterator<Item = Symmetry> {
+        (0..8).map(Symmetry)
+    }
+
+    //(行, 列)を変換する。0..4は90度ずつの回転、4..8はそれに左右反転を加えたもの
+    fn map(&self, r: i8, c: i8, offset: i8) -> (i8, i8) {
+        let (r, c) = if self.0 >= 4 { (r, offset - c) } else { (r, c) };
+        match self.0 % 4 {
+            0 => (r, c),
+            1 => (c, offset - r),
+            2 => (offset - r, offset - c),
+            _ => (offset - c, r),
+        }
+    }
+
+    pub fn inverse(&self) -> Symmetry {
+        //鏡映を含むものは自分自身が逆変換
+        match self.0 {
+            1 => Symmetry(3),
+            3 => Symmetry(1),
+            n => Symmetry(n),
+        }
+    }
+
+    fn map_idx(&self, idx: u8) -> u8 {
+        let (r, c) = self.map(
+            (idx as u64 / BITBOD_WIDTH) as i8,
+            (idx as u64 % BITBOD_WIDTH) as i8,
+            LAST,
+        );
+        r as u8 * BITBOD_WIDTH as u8 + c as u8
+    }
+
+    fn map_bod(&self, bod: u64) -> u64 {
+        let mut result = 0;
+        for idx in 0..64 {
+            if bod >> idx & 1 == 1 {
+                result |= 1u64 << self.map_idx(idx);
+            }
+        }
+        result
+    }
+
+    pub fn apply_board(&self, board: &Bitboard) -> Bitboard {
+        Bitboard {
+            player_bods: board.player_bods.map(|bod| self.map_bod(bod)),
+            ..*board
+        }
+    }
+
+    pub fn apply_move(&self, mv: MoveBit) -> MoveBit {
+        let angle_idx = if mv.angle_idx < 8 {
+            //向きは平行移動を含まないので原点を中心に変換する
+            let (dr, dc) = DIRECTIONS[mv.angle_idx as usize];
+            let direction = self.map(dr, dc, 0);
+            DIRECTIONS.iter().position(|&d| d == direction).unwrap() as u8
+        } else {
+            mv.angle_idx
+        };
+        MoveBit::from_idx(self.map_idx(mv.idx), angle_idx)
+    }
+}
+
+//対称な局面で同じになるキー。変換後の盤面のto_compression_bod()の最小値と、その変換を返す
+pub fn canonical_key(board: &Bitboard) -> (u64, Symmetry) {
+    Symmetry::all()
+        .map(|sym| (sym.apply_board(board).to_compression_bod(), sym))
+        .min_by_key(|&(key, _)| key)
+        .unwrap()
+}
+
+#[test]
+fn test_symmetry_preserves_rules() {
+    use crate::random_state_generator::random_state_generator;
+
+    for _ in 0..50 {
+        let (board, _) = random_state_generator(8);
+        if board.game_over() {
+            continue;
+        }
+        for sym in Symmetry::all() {
+            let mapped = sym.apply_board(&board);
+            let inverse = sym.inverse();
+            assert_eq!(
+                inverse.apply_board(&mapped).to_compression_bod(),
+                board.to_compression_bod()
+            );
+
+            //合法手は変換後の局面の合法手に移り、指した結果も変換した局面になる
+            let mut moves: Vec<_> = board.iter_legal_move().map(|m| sym.apply_move(m)).collect();
+            let mut expected: Vec<_> = mapped.iter_legal_move().collect();
+            moves.sort_by_key(|m| (m.idx, m.angle_idx));
+            expected.sort_by_key(|m| (m.idx, m.angle_idx));
+            assert_eq!(moves, expected);
+            for mv in board.iter_legal_move() {
+                let mut after = board;
+                let mut mapped_after = mapped;
+                if after.apply_force_with_check_illegal_move(mv, None).is_err()
+                    || mapped_after
+                        .apply_force_with_check_illegal_move(sym.apply_move(mv), None)
+                        .is_err()
+                {
+                    continue;
+                }
+                assert_eq!(
+                    sym.apply_board(&after).to_compression_bod(),
+                    mapped_after.to_compression_bod()
+                );
+                assert_eq!(inverse.apply_move(sym.apply_move(mv)), mv);
+            }
+        }
+        assert_eq!(
+            canonical_key(&board).0,
+            canonical_key(&Symmetry(5).apply_board(&board)).0
+        );
+    }
+}
@@ -0,0 +1,326 @@
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::sync::atomic::{AtomicU8, AtomicU64, Ordering};
+
+use crate::bitboard::MoveBit;
+use crate::util::splitmix64;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TTFlag {
+    Exact,      // このスコアは真の評価値 (alpha < score < beta)
+    LowerBound, // このスコアは下限値 (score >= beta, betaカットで得られた)
+    UpperBound, // このBitboardは上限値 (score <= alpha, 有望な手が見つからなかった)
+}
+
+// 置換表に保存するデータ構造
+#[derive(Clone, Copy, Debug)]
+pub struct TTEntry {
+    pub score: i16,
+    pub depth: u8, // 保存したときの探索深さ
+    pub flag: TTFlag,
+    pub best_move: MoveBit, // その局面で見つかった最善手
+}
+
+const BUCKET_SIZE: usize = 4;
+
+//置換表ファイルの先頭
+const FILE_MAGIC: &[u8; 8] = b"VIDROTT1";
+
+//世代が1つ古くなるごとに深さ何手分価値を下げるか
+const AGE_WEIGHT: i32 = 8;
+
+//data(u64)のビット配置
+//  0..16 score, 16..24 depth, 24..26 flag(0は空), 26..34 move.idx, 34..38 move.angle_idx, 38..46 generation
+fn pack(entry: &TTEntry, generation: u8) -> u64 {
+    let flag: u64 = match entry.flag {
+        TTFlag::Exact => 1,
+        TTFlag::LowerBound => 2,
+        TTFlag::UpperBound => 3,
+    };
+    (entry.score as u16 as u64)
+        | (entry.depth as u64) << 16
+        | flag << 24
+        | (entry.best_move.idx as u64) << 26
+        | ((entry.best_move.angle_idx & 0xF) as u64) << 34
+        | (generation as u64) << 38
+}
+
+fn unpack(data: u64) -> TTEntry {
+    let flag = match (data >> 24) & 0b11 {
+        1 => TTFlag::Exact,
+        2 => TTFlag::LowerBound,
+        _ => TTFlag::UpperBound,
+    };
+    TTEntry {
+        score: data as u16 as i16,
+        depth: (data >> 16) as u8,
+        flag,
+        best_move: MoveBit::from_idx((data >> 26) as u8, ((data >> 34) & 0xF) as u8),
+    }
+}
+
+fn data_depth(data: u64) -> u8 {
+    (data >> 16) as u8
+}
+
+fn data_generation(data: u64) -> u8 {
+    (data >> 38) as u8
+}
+
+fn is_empty(data: u64) -> bool {
+    (data >> 24) & 0b11 == 0
+}
+
+// key ^ data を一緒に書き込むことで、読み出し時に片方だけ更新された(破れた)エントリを検出する
+#[derive(Default)]
+struct Slot {
+    key_xor_data: AtomicU64,
+    data: AtomicU64,
+}
+
+impl Slot {
+    fn load(&self) -> (u64, u64) {
+        let data = self.data.load(Ordering::Relaxed);
+        let key = self.key_xor_data.load(Ordering::Relaxed) ^ data;
+        (key, data)
+    }
+    fn write(&self, key: u64, data: u64) {
+        self.data.store(data, Ordering::Relaxed);
+        self.key_xor_data.store(key ^ data, Ordering::Relaxed);
+    }
+}
+
+//1バケット = 1キャッシュライン
+#[repr(align(64))]
+#[derive(Default)]
+struct Bucket {
+    slots: [Slot; BUCKET_SIZE],
+}
+
+pub struct TranspositionTable {
+    buckets: Vec<Bucket>,
+    generation: AtomicU8,
+}
+
+impl TranspositionTable {
+    pub fn new(size_mb: usize) -> Self {
+        let num_buckets = (size_mb * 1024 * 1024 / size_of::<Bucket>()).max(1);
+        Self {
+            buckets: (0..num_buckets).map(|_| Bucket::default()).collect(),
+            generation: AtomicU8::new(0),
+        }
+    }
+
+    //to_compression_bod()は盤面そのものなので混ぜてからバケットを決める
+    fn bucket(&self, key: u64) -> &Bucket {
+        let x = splitmix64(key);
+        let idx = ((x as u128 * self.buckets.len() as u128) >> 64) as usize;
+        &self.buckets[idx]
+    }
+
+    pub fn probe(&self, key: u64) -> Option<TTEntry> {
+        self.bucket(key)
+            .slots
+            .iter()
+            .map(|slot| slot.load())
+            .find(|&(k, data)| !is_empty(data) && k == key)
+            .map(|(_, data)| unpack(data))
+    }
+
+    //書き込んだらtrue(同じ局面のより深い結果を残した場合はfalse)
+    pub fn store(&self, key: u64, entry: TTEntry) -> bool {
+        let generation = self.generation();
+        let bucket = self.bucket(key);
+
+        let mut replace = &bucket.slots[0];
+        let mut replace_value = i32::MAX;
+        for slot in bucket.slots.iter() {
+            let (k, data) = slot.load();
+            if is_empty(data) {
+                replace = slot;
+                break;
+            }
+            if k == key {
+                //同じ局面は浅い探索結果で深い結果を潰さない(古い世代かExactなら上書き)
+                if entry.depth.saturating_add(2) < data_depth(data)
+                    && data_generation(data) == generation
+                    && entry.flag != TTFlag::Exact
+                {
+                    return false;
+                }
+                replace = slot;
+                break;
+            }
+            //深さが浅く古いものほど置き換えやすい
+            let age = generation.wrapping_sub(data_generation(data)) as i32;
+            let value = data_depth(data) as i32 - AGE_WEIGHT * age;
+            if value < replace_value {
+                replace_value = value;
+                replace = slot;
+            }
+        }
+        replace.write(key, pack(&entry, generation));
+        true
+    }
+
+    //手を指すたびに呼ぶ。エントリは消さずに古い世代として置き換えやすくする
+    pub fn new_search(&self) {
+        self.generation.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn generation(&self) -> u8 {
+        self.generation.load(Ordering::Relaxed)
+    }
+
+    //すべてのエントリを消して世代も最初に戻す
+    pub fn clear(&self) {
+        for slot in self.buckets.iter().flat_map(|b| b.slots.iter()) {
+            slot.write(0, 0);
+        }
+        self.generation.store(0, Ordering::Relaxed);
+    }
+
+    //現在の世代のエントリが占める割合(千分率)
+    pub fn hashfull(&self) -> usize {
+        let generation = self.generation();
+        let sample = self.buckets.len().min(1000);
+        let used = self.buckets[..sample]
+            .iter()
+            .flat_map(|b| b.slots.iter())
+            .filter(|slot| {
+                let (_, data) = slot.load();
+                !is_empty(data) && data_generation(data) == generation
+            })
+            .count();
+        used * 1000 / (sample * BUCKET_SIZE)
+    }
+
+    //残り深さmin_depth以上のエントリをファイルに保存し、保存した数を返す。
+    //versionにはモデルと探索設定のハッシュを渡し、読み込むときに一致するか確かめる
+    pub fn save(&self, file_path: &str, version: u64, min_depth: u8) -> std::io::Result<usize> {
+        let entries: Vec<(u64, u64)> = self
+            .buckets
+            .iter()
+            .flat_map(|b| b.slots.iter())
+            .map(|slot| slot.load())
+            .filter(|&(_, data)| !is_empty(data) && data_depth(data) >= min_depth)
+            .collect();
+
+        let mut writer = BufWriter::new(File::create(file_path)?);
+        writer.write_all(FILE_MAGIC)?;
+        writer.write_all(&version.to_le_bytes())?;
+        writer.write_all(&(entries.len() as u64).to_le_bytes())?;
+        for (key, data) in entries.iter() {
+            //世代は読み込んだ側の現在の世代にするので保存しない
+            writer.write_all(&key.to_le_bytes())?;
+            writer.write_all(&pack(&unpack(*data), 0).to_le_bytes())?;
+        }
+        writer.flush()?;
+        Ok(entries.len())
+    }
+
+    //saveしたファイルのエントリを現在の世代として加え、置換表に書き込んだ数を返す。
+    //versionが違うファイルは古いモデルや設定の結果なのでInvalidDataのエラーにする
+    pub fn load(&self, file_path: &str, version: u64) -> std::io::Result<usize> {
+        let mut reader = BufReader::new(File::open(file_path)?);
+        let mut magic = [0u8; 8];
+        reader.read_exact(&mut magic)?;
+        if &magic != FILE_MAGIC {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "置換表ファイルではありません",
+            ));
+        }
+        let mut read_u64 = || -> std::io::Result<u64> {
+            let mut buf = [0u8; 8];
+            reader.read_exact(&mut buf)?;
+            Ok(u64::from_le_bytes(buf))
+        };
+        let file_version = read_u64()?;
+        if file_version != version {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!(
+                    "バージョンが違います(ファイル: {:016x}, 現在: {:016x})",
+                    file_version, version
+                ),
+            ));
+        }
+        let len = read_u64()?;
+        let mut stored = 0;
+        for _ in 0..len {
+            let key = read_u64()?;
+            let data = read_u64()?;
+            if !is_empty(data) && self.store(key, unpack(data)) {
+                stored += 1;
+            }
+        }
+        Ok(stored)
+    }
+}
+
+#[test]
+fn test_store_and_probe() {
+    let tt = TranspositionTable::new(1);
+    let entry = TTEntry {
+        score: -1234,
+        depth: 7,
+        flag: TTFlag::LowerBound,
+        best_move: MoveBit::from_idx(40, 8),
+    };
+    tt.store(12345, entry);
+
+    let found = tt.probe(12345).expect("stored entry must be found");
+    assert_eq!(found.score, -1234);
+    assert_eq!(found.depth, 7);
+    assert_eq!(found.flag, TTFlag::LowerBound);
+    assert_eq!(found.best_move, MoveBit::from_idx(40, 8));
+    assert!(tt.probe(54321).is_none());
+
+    //世代が進んでもエントリは残る
+    tt.new_search();
+    assert!(tt.probe(12345).is_some());
+
+    //clearでエントリも世代も消える
+    tt.clear();
+    assert!(tt.probe(12345).is_none());
+    assert_eq!(tt.generation(), 0);
+}
+
+#[test]
+fn test_save_and_load() {
+    let tt = TranspositionTable::new(1);
+    let entry = |score, depth, flag| TTEntry {
+        score,
+        depth,
+        flag,
+        best_move: MoveBit::from_idx(20, 3),
+    };
+    tt.store(111, entry(50, 6, TTFlag::Exact));
+    tt.store(222, entry(-30, 1, TTFlag::Exact));
+    tt.store(333, entry(10, 3, TTFlag::LowerBound));
+
+    let path = std::env::temp_dir().join(format!("vidro_test_tt_{}.bin", std::process::id()));
+    let path = path.to_str().unwrap();
+    //浅いエントリは保存しない
+    assert_eq!(tt.save(path, 42, 2).unwrap(), 2);
+
+    //既にある深い結果はファイルの浅い結果で上書きしないので、書き込んだ数には含めない
+    let loaded = TranspositionTable::new(1);
+    loaded.new_search();
+    loaded.store(333, entry(20, 9, TTFlag::LowerBound));
+    assert_eq!(loaded.load(path, 42).unwrap(), 1);
+    assert_eq!(loaded.probe(333).unwrap().depth, 9);
+    let found = loaded.probe(111).unwrap();
+    assert_eq!(found.score, 50);
+    assert_eq!(found.depth, 6);
+    assert_eq!(found.best_move, MoveBit::from_idx(20, 3));
+    assert!(loaded.probe(222).is_none());
+
+    //モデルや設定が変わったファイルは読み込まない
+    let stale = TranspositionTable::new(1);
+    let err = stale.load(path, 43).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    assert!(stale.probe(111).is_none());
+    std::fs::remove_file(path).unwrap();
+}
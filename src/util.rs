@@ -2,6 +2,7 @@ use std::fs::File;
 use std::io::{BufReader, BufWriter};
 
 use crate::eval::AiModel;
+use crate::search::SearchOptions;
 
 pub fn save_model(model: &AiModel, file_path: &str) -> std::io::Result<()> {
     let file = File::create(file_path)?;
@@ -24,3 +25,29 @@ pub fn load_model(file_path: &str) -> std::io::Result<AiModel> {
     println!("Loaded model");
     Ok(model)
 }
+
+//置換表ファイルのバージョン。モデルの重みと評価値に影響する探索設定のFNV-1aハッシュ
+pub fn cache_version(model: &AiModel, options: &SearchOptions) -> u64 {
+    let mut bytes = bincode::serialize(model).unwrap_or_default();
+    bytes.extend(serde_json::to_vec(&options.params).unwrap_or_default());
+    bytes.extend((options.quiescence_depth as u64).to_le_bytes());
+    bytes.extend(options.contempt.to_le_bytes());
+    fnv1a(FNV_OFFSET_BASIS, &bytes)
+}
+
+pub const FNV_OFFSET_BASIS: u64 = 0xCBF2_9CE4_8422_2325;
+
+//FNV-1aでbytesをhashに混ぜる
+pub fn fnv1a(hash: u64, bytes: &[u8]) -> u64 {
+    bytes.iter().fold(hash, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0000_0100_0000_01B3)
+    })
+}
+
+//splitmix64。偏りのある64bit値(盤面のビット列など)を一様に近い値に混ぜる
+pub fn splitmix64(x: u64) -> u64 {
+    let mut x = x.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    x ^ (x >> 31)
+}